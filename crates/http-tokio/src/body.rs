@@ -0,0 +1,355 @@
+use crate::encoding::{ContentEncoding, Decoder};
+use crate::headers::Headers;
+use crate::request::{read_line, Limits, Request, RequestError};
+use std::result::Result as StdResult;
+use tokio::io::{AsyncBufRead, AsyncReadExt, BufReader};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+type Result<T> = StdResult<T, RequestError>;
+
+const MAX_CHUNK_LINE: usize = 1024;
+
+/// The reader handed back by a dropped [`BodyReader`], `None` if the connection must be closed
+pub(crate) type Release = oneshot::Sender<Option<BufReader<OwnedReadHalf>>>;
+
+/// The body of a request
+#[derive(Debug)]
+pub enum Body {
+    /// the whole body, read before the request was handed over
+    Full(Vec<u8>),
+    /// a body still to be read from the connection
+    Stream(BodyReader),
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Body::Full(Vec::new())
+    }
+}
+
+/// How the end of the body is determined
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Framing {
+    Length(usize),
+    Chunked,
+    /// the body ends when the connection is closed, only for responses
+    #[cfg_attr(not(feature = "client"), allow(dead_code))]
+    Close,
+}
+
+impl Framing {
+    /// Determines the framing from the request headers, `None` if the body is empty
+    pub(crate) fn of(req: &Request) -> Result<Option<Self>> {
+        // without a final chunked coding only closing the connection would end the body, which a client can't do
+        let encoding = req.headers.get_joined("Transfer-Encoding");
+        let final_coding = encoding.as_deref().and_then(|encoding| encoding.rsplit(',').next()).map(str::trim);
+        if final_coding.is_some_and(|coding| !coding.eq_ignore_ascii_case("chunked")) && !req.headers.contains("Content-Length") {
+            return Err(RequestError::LengthRequired);
+        }
+        Self::from_headers(&req.headers)
+    }
+
+    /// Determines the framing from `Transfer-Encoding` and `Content-Length`, `None` if neither is set
+    pub(crate) fn from_headers(headers: &Headers) -> Result<Option<Self>> {
+        match (headers.get_joined("Transfer-Encoding"), headers.get_joined("Content-Length")) {
+            (Some(_), Some(_)) => Err(RequestError::AmbiguousLength),
+            (Some(encoding), None) => {
+                // chunked must be the final encoding, any other is not supported
+                if !encoding.trim().eq_ignore_ascii_case("chunked") {
+                    return Err(RequestError::TransferEncoding(encoding));
+                }
+                Ok(Some(Framing::Chunked))
+            }
+            (None, Some(len)) => {
+                // repeated Content-Length fields are accepted only when they agree
+                let mut lengths = len.split(',').map(str::trim);
+                let first = lengths.next().unwrap_or_default();
+                if lengths.any(|l| l != first) {
+                    return Err(RequestError::ContentLength(len));
+                }
+                let len = str::parse::<usize>(first).map_err(|_| RequestError::ContentLength(len.clone()))?;
+                Ok(Some(Framing::Length(len)))
+            }
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// Removes the `Content-Encoding` header of a body that will be decoded transparently
+pub(crate) fn take_content_encoding(headers: &mut Headers) -> Result<Option<ContentEncoding>> {
+    let Some(value) = headers.get_joined("Content-Encoding") else {
+        return Ok(None);
+    };
+    let encoding = ContentEncoding::from_header(&value).map_err(RequestError::ContentEncoding)?;
+    headers.remove("Content-Encoding");
+    Ok(encoding)
+}
+
+/// Reads and decodes the whole body, both the received and the decoded size are limited
+pub(crate) async fn read_full<R: AsyncBufRead + Unpin>(
+    buf_reader: &mut R,
+    framing: Framing,
+    encoding: Option<ContentEncoding>,
+    limits: &Limits,
+) -> Result<Vec<u8>> {
+    if let Framing::Length(len) = framing {
+        if len > limits.max_body_size {
+            return Err(RequestError::BodyTooLarge);
+        }
+    }
+    let mut decoder = BodyDecoder::new(framing);
+    let mut content = encoding.map(|encoding| Decoder::new(encoding, limits.max_body_size));
+    let mut received = 0;
+    let mut body = Vec::new();
+    let mut buf = vec![0_u8; 8 * 1024];
+    loop {
+        let read = decoder.read(buf_reader, &mut buf, limits).await?;
+        if read == 0 {
+            break;
+        }
+        received += read;
+        if received > limits.max_body_size {
+            return Err(RequestError::BodyTooLarge);
+        }
+        match content.as_mut() {
+            Some(content) => body.extend(content.write(&buf[..read]).map_err(decode_error)?),
+            None => body.extend_from_slice(&buf[..read]),
+        }
+    }
+    if let Some(content) = content {
+        body.extend(content.finish().map_err(decode_error)?);
+    }
+    Ok(body)
+}
+
+/// The decoder fails with [`std::io::ErrorKind::FileTooLarge`] past the body size limit
+fn decode_error(err: std::io::Error) -> RequestError {
+    match err.kind() {
+        std::io::ErrorKind::FileTooLarge => RequestError::BodyTooLarge,
+        _ => RequestError::Decode(err),
+    }
+}
+
+#[derive(Debug)]
+enum DecodeState {
+    Length(usize),
+    ChunkStart,
+    Chunk(usize),
+    UntilClose,
+    Done,
+}
+
+/// Removes the framing from the body as it is read
+#[derive(Debug)]
+pub(crate) struct BodyDecoder {
+    state: DecodeState,
+    line: Vec<u8>,
+}
+
+impl BodyDecoder {
+    pub(crate) fn new(framing: Framing) -> Self {
+        let state = match framing {
+            Framing::Length(len) => DecodeState::Length(len),
+            Framing::Chunked => DecodeState::ChunkStart,
+            Framing::Close => DecodeState::UntilClose,
+        };
+        Self { state, line: Vec::new() }
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        matches!(self.state, DecodeState::Done | DecodeState::Length(0))
+    }
+
+    /// Reads the next bytes of the body into `buf`, 0 once the body is over
+    pub(crate) async fn read<R: AsyncBufRead + Unpin>(&mut self, buf_reader: &mut R, buf: &mut [u8], limits: &Limits) -> Result<usize> {
+        loop {
+            match self.state {
+                DecodeState::Done | DecodeState::Length(0) => return Ok(0),
+                DecodeState::Length(remaining) => {
+                    let read = read_some(buf_reader, buf, remaining).await?;
+                    self.state = DecodeState::Length(remaining - read);
+                    return Ok(read);
+                }
+                DecodeState::ChunkStart => {
+                    let (_, size_line) = read_line(buf_reader, &mut self.line, MAX_CHUNK_LINE)
+                        .await?
+                        .ok_or(RequestError::Chunk("chunk size line too long"))?;
+                    // chunk extensions after `;` are ignored
+                    let size = size_line.split(';').next().unwrap_or("").trim();
+                    let size = usize::from_str_radix(size, 16).map_err(|_| RequestError::Chunk("invalid chunk size"))?;
+                    if size == 0 {
+                        self.skip_trailers(buf_reader, limits).await?;
+                        self.state = DecodeState::Done;
+                    } else {
+                        self.state = DecodeState::Chunk(size);
+                    }
+                }
+                DecodeState::Chunk(0) => {
+                    let (_, terminator) = read_line(buf_reader, &mut self.line, MAX_CHUNK_LINE)
+                        .await?
+                        .ok_or(RequestError::Chunk("missing chunk terminator"))?;
+                    if !terminator.is_empty() {
+                        return Err(RequestError::Chunk("missing chunk terminator"));
+                    }
+                    self.state = DecodeState::ChunkStart;
+                }
+                DecodeState::Chunk(remaining) => {
+                    let read = read_some(buf_reader, buf, remaining).await?;
+                    self.state = DecodeState::Chunk(remaining - read);
+                    return Ok(read);
+                }
+                DecodeState::UntilClose => {
+                    let read = buf_reader.read(buf).await?;
+                    if read == 0 {
+                        self.state = DecodeState::Done;
+                    }
+                    return Ok(read);
+                }
+            }
+        }
+    }
+
+    /// Trailer fields are discarded
+    async fn skip_trailers<R: AsyncBufRead + Unpin>(&mut self, buf_reader: &mut R, limits: &Limits) -> Result<()> {
+        let mut trailer_size = 0;
+        loop {
+            let (len, trailer) = read_line(buf_reader, &mut self.line, limits.max_header_size - trailer_size)
+                .await?
+                .ok_or(RequestError::HeadersTooLarge)?;
+            if trailer.is_empty() {
+                return Ok(());
+            }
+            trailer_size += len;
+        }
+    }
+}
+
+/// Reads at most `remaining` bytes, the body can't end before
+async fn read_some<R: AsyncBufRead + Unpin>(buf_reader: &mut R, buf: &mut [u8], remaining: usize) -> Result<usize> {
+    let max = remaining.min(buf.len());
+    let read = buf_reader.read(&mut buf[..max]).await?;
+    if read == 0 && max > 0 {
+        return Err(RequestError::Read(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+    Ok(read)
+}
+
+/// Reads a request body from the connection as it arrives.
+///
+/// The connection serves the next request once the reader is dropped,
+/// if the body was not read to the end the connection is closed.
+pub struct BodyReader {
+    reader: Option<BufReader<OwnedReadHalf>>,
+    decoder: BodyDecoder,
+    /// content decoder, `None` once the compressed stream is over
+    content: Option<Box<Decoder>>,
+    /// decoded bytes not yet returned
+    decoded: Vec<u8>,
+    /// decoded bytes returned so far, at most `limits.max_body_size`
+    returned: usize,
+    limits: Limits,
+    release: Option<Release>,
+}
+
+impl BodyReader {
+    pub(crate) fn new(
+        reader: BufReader<OwnedReadHalf>,
+        framing: Framing,
+        encoding: Option<ContentEncoding>,
+        limits: Limits,
+        release: Release,
+    ) -> Self {
+        Self {
+            reader: Some(reader),
+            decoder: BodyDecoder::new(framing),
+            content: encoding.map(|encoding| Box::new(Decoder::new(encoding, limits.max_body_size))),
+            decoded: Vec::new(),
+            returned: 0,
+            limits,
+            release: Some(release),
+        }
+    }
+
+    /// Reads the next bytes of the body into `buf`, 0 once the body is over.
+    ///
+    /// Compressed bodies are decoded, each read must complete within the body timeout
+    /// and the decoded body can't be larger than the size limit.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let read = self.read_decoded(buf).await?;
+        self.returned += read;
+        if self.returned > self.limits.max_body_size {
+            return Err(RequestError::BodyTooLarge);
+        }
+        Ok(read)
+    }
+
+    async fn read_decoded(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            if !self.decoded.is_empty() {
+                let len = buf.len().min(self.decoded.len());
+                buf[..len].copy_from_slice(&self.decoded[..len]);
+                self.decoded.drain(..len);
+                return Ok(len);
+            }
+            if self.content.is_none() {
+                return self.read_raw(buf).await;
+            }
+
+            let mut raw = vec![0_u8; buf.len().max(1024)];
+            let read = self.read_raw(&mut raw).await?;
+            if read == 0 {
+                let content = self.content.take().expect("content decoder checked above");
+                self.decoded = content.finish().map_err(decode_error)?;
+                if self.decoded.is_empty() {
+                    return Ok(0);
+                }
+            } else if let Some(content) = self.content.as_mut() {
+                self.decoded = content.write(&raw[..read]).map_err(decode_error)?;
+            }
+        }
+    }
+
+    async fn read_raw(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let reader = self.reader.as_mut().expect("body reader is only taken on drop");
+        timeout(self.limits.body_timeout, self.decoder.read(reader, buf, &self.limits))
+            .await
+            .map_err(|_| RequestError::Timeout)?
+    }
+
+    /// Reads the rest of the body within the size limit
+    pub async fn read_to_end(&mut self) -> Result<Vec<u8>> {
+        let mut body = Vec::new();
+        let mut buf = vec![0_u8; 8 * 1024];
+        loop {
+            let read = self.read(&mut buf).await?;
+            if read == 0 {
+                return Ok(body);
+            }
+            body.extend_from_slice(&buf[..read]);
+        }
+    }
+
+    /// Whether the whole body has been read
+    pub fn is_done(&self) -> bool {
+        self.decoder.is_done()
+    }
+}
+
+impl std::fmt::Debug for BodyReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BodyReader").field("decoder", &self.decoder).finish()
+    }
+}
+
+/// Hands the reader back to the connection, which is reused only after the whole body was read
+impl Drop for BodyReader {
+    fn drop(&mut self) {
+        let reader = self.reader.take();
+        if let Some(release) = self.release.take() {
+            let done = self.decoder.is_done();
+            let _ = release.send(reader.filter(|_| done));
+        }
+    }
+}
@@ -0,0 +1,441 @@
+use crate::body::{self, Framing};
+use crate::request::{read_headers, read_line, Limits, RequestError};
+use crate::Headers;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::io;
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::rustls::{self, pki_types::ServerName, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("invalid url `{0}`")]
+    Url(String),
+    #[error("could not connect to {0}: {1}")]
+    Connect(String, io::Error),
+    #[error("timed out connecting to {0}")]
+    ConnectTimeout(String),
+    #[error("could not send the request: {0}")]
+    Write(io::Error),
+    #[error("invalid response: {0}")]
+    Response(#[from] RequestError),
+    #[error("token authentication failed: {0}")]
+    Token(String),
+    #[error("invalid JSON body: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+type Result<T> = StdResult<T, ClientError>;
+
+/// An HTTP/1.1 client, each request is sent on a new connection.
+///
+/// `https` servers are verified against the Mozilla root certificates. A `401` with a
+/// `WWW-Authenticate: Bearer realm=...` challenge is answered with the Docker token flow:
+/// a token is requested to the realm, with the basic credentials if any, and the request
+/// is sent again. Tokens are kept per host until they are rejected.
+pub struct Client {
+    tls: TlsConnector,
+    credentials: Option<Credentials>,
+    limits: Limits,
+    connect_timeout: Duration,
+    tokens: Mutex<HashMap<String, String>>,
+}
+
+#[derive(Debug, Clone)]
+enum Credentials {
+    Basic(String),
+    Bearer(String),
+}
+
+impl Credentials {
+    fn header(&self) -> String {
+        match self {
+            Credentials::Basic(encoded) => format!("Basic {encoded}"),
+            Credentials::Bearer(token) => format!("Bearer {token}"),
+        }
+    }
+}
+
+impl Client {
+    pub fn new() -> Self {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default protocol versions")
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Self {
+            tls: TlsConnector::from(Arc::new(config)),
+            credentials: None,
+            limits: Limits::default(),
+            connect_timeout: Duration::from_secs(10),
+            tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sends `Authorization: Basic`, also used to request tokens
+    pub fn basic_auth(mut self, username: &str, password: &str) -> Self {
+        self.credentials = Some(Credentials::Basic(BASE64.encode(format!("{username}:{password}"))));
+        self
+    }
+
+    /// Sends `Authorization: Bearer` with a fixed token, the token flow is not followed
+    pub fn bearer_auth(mut self, token: &str) -> Self {
+        self.credentials = Some(Credentials::Bearer(token.to_owned()));
+        self
+    }
+
+    /// Bounds on the responses, the status line is limited by `max_request_line`
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Time allowed to open the connection, TLS handshake included
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    pub fn get(&self, url: &str) -> ClientRequest<'_> {
+        self.request("GET", url)
+    }
+
+    pub fn head(&self, url: &str) -> ClientRequest<'_> {
+        self.request("HEAD", url)
+    }
+
+    pub fn post(&self, url: &str) -> ClientRequest<'_> {
+        self.request("POST", url)
+    }
+
+    pub fn request(&self, method: &str, url: &str) -> ClientRequest<'_> {
+        ClientRequest {
+            client: self,
+            method: method.to_owned(),
+            url: url.to_owned(),
+            headers: Headers::new(),
+            body: Vec::new(),
+        }
+    }
+
+    async fn connect(&self, url: &Url) -> Result<Box<dyn Io>> {
+        let connect = async {
+            let stream = TcpStream::connect((url.host.as_str(), url.port))
+                .await
+                .map_err(|err| ClientError::Connect(url.authority.clone(), err))?;
+            if !url.tls {
+                return Ok(Box::new(stream) as Box<dyn Io>);
+            }
+            let name = ServerName::try_from(url.host.clone()).map_err(|_| ClientError::Url(url.authority.clone()))?;
+            let stream = self
+                .tls
+                .connect(name, stream)
+                .await
+                .map_err(|err| ClientError::Connect(url.authority.clone(), err))?;
+            Ok(Box::new(stream) as Box<dyn Io>)
+        };
+        timeout(self.connect_timeout, connect)
+            .await
+            .map_err(|_| ClientError::ConnectTimeout(url.authority.clone()))?
+    }
+
+    /// Sends a single request, without following the token flow
+    async fn execute(&self, req: &ClientRequest<'_>, url: &Url, authorization: Option<&str>) -> Result<ClientResponse> {
+        let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", req.method, url.target, url.authority);
+        let mut add_default = |name: &str, value: &str| {
+            if !req.headers.contains(name) {
+                head += &format!("{name}: {value}\r\n");
+            }
+        };
+        add_default("User-Agent", concat!("http-tokio/", env!("CARGO_PKG_VERSION")));
+        add_default("Accept-Encoding", "gzip, deflate");
+        if let Some(authorization) = authorization {
+            add_default("Authorization", authorization);
+        }
+        if !req.body.is_empty() || matches!(req.method.as_str(), "POST" | "PUT" | "PATCH") {
+            head += &format!("Content-Length: {}\r\n", req.body.len());
+        }
+        head += "Connection: close\r\n";
+        for (k, v) in req.headers.iter() {
+            head += &format!("{k}: {v}\r\n");
+        }
+        head += "\r\n";
+
+        let mut stream = self.connect(url).await?;
+        let write = async {
+            stream.write_all(head.as_bytes()).await?;
+            stream.write_all(&req.body).await?;
+            stream.flush().await
+        };
+        timeout(self.limits.body_timeout, write)
+            .await
+            .map_err(|_| ClientError::Write(io::ErrorKind::TimedOut.into()))?
+            .map_err(ClientError::Write)?;
+
+        let mut reader = BufReader::new(stream);
+        let (status, reason, mut headers) = timeout(self.limits.header_timeout, read_head(&mut reader, &self.limits))
+            .await
+            .map_err(|_| RequestError::Timeout)??;
+        // responses to HEAD, 204 and 304 never have a body
+        let body = match req.method == "HEAD" || status == 204 || status == 304 {
+            true => Vec::new(),
+            false => {
+                let framing = Framing::from_headers(&headers)?.unwrap_or(Framing::Close);
+                let encoding = body::take_content_encoding(&mut headers)?;
+                timeout(self.limits.body_timeout, body::read_full(&mut reader, framing, encoding, &self.limits))
+                    .await
+                    .map_err(|_| RequestError::Timeout)??
+            }
+        };
+        Ok(ClientResponse {
+            status,
+            reason,
+            headers,
+            body,
+        })
+    }
+
+    /// Requests a token to the realm of the challenge
+    async fn fetch_token(&self, challenge: &HashMap<String, String>) -> Result<String> {
+        let realm = challenge
+            .get("realm")
+            .ok_or_else(|| ClientError::Token("challenge without realm".to_owned()))?;
+        let query = ["service", "scope"]
+            .iter()
+            .filter_map(|param| challenge.get(*param).map(|value| format!("{param}={}", encode_query(value))))
+            .collect::<Vec<_>>();
+        let mut url = realm.clone();
+        if !query.is_empty() {
+            url += if url.contains('?') { "&" } else { "?" };
+            url += &query.join("&");
+        }
+
+        let req = self.get(&url);
+        let authorization = self.credentials.as_ref().map(Credentials::header);
+        let res = self.execute(&req, &Url::parse(&url)?, authorization.as_deref()).await?;
+        if !res.is_success() {
+            return Err(ClientError::Token(format!("{realm} answered {} {}", res.status, res.reason)));
+        }
+
+        // `access_token` is the OAuth2 name of the same token
+        let body = res.json::<serde_json::Value>()?;
+        body.get("token")
+            .or(body.get("access_token"))
+            .and_then(serde_json::Value::as_str)
+            .map(str::to_owned)
+            .ok_or_else(|| ClientError::Token(format!("{realm} sent no token")))
+    }
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A request built with [`Client::request`]
+pub struct ClientRequest<'a> {
+    client: &'a Client,
+    method: String,
+    url: String,
+    headers: Headers,
+    body: Vec<u8>,
+}
+
+impl ClientRequest<'_> {
+    /// Sets a header, `Host`, `Content-Length` and `Connection` are set by the client
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Serializes the value as the JSON body
+    pub fn json<T: serde::Serialize>(mut self, value: &T) -> Result<Self> {
+        self.body = serde_json::to_vec(value)?;
+        Ok(self.header("Content-Type", "application/json"))
+    }
+
+    /// Sends the request, a `401` is returned as is unless a token can be obtained
+    pub async fn send(self) -> Result<ClientResponse> {
+        let client = self.client;
+        let url = Url::parse(&self.url)?;
+        let token = client.tokens.lock().unwrap().get(&url.authority).cloned();
+        let authorization = match token {
+            Some(token) => Some(format!("Bearer {token}")),
+            None => client.credentials.as_ref().map(Credentials::header),
+        };
+        let res = client.execute(&self, &url, authorization.as_deref()).await?;
+
+        if res.status != 401 || matches!(client.credentials, Some(Credentials::Bearer(_))) {
+            return Ok(res);
+        }
+        let Some(challenge) = res.headers.get("WWW-Authenticate").and_then(parse_bearer_challenge) else {
+            return Ok(res);
+        };
+        let token = client.fetch_token(&challenge).await?;
+        client.tokens.lock().unwrap().insert(url.authority.clone(), token.clone());
+        client.execute(&self, &url, Some(&format!("Bearer {token}"))).await
+    }
+}
+
+/// A whole response, the body is decoded when compressed
+#[derive(Debug)]
+pub struct ClientResponse {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+impl ClientResponse {
+    /// Whether the status is 2xx
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+    /// The first value of the header, names are case-insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+    pub fn text(&self) -> StdResult<&str, std::str::Utf8Error> {
+        std::str::from_utf8(&self.body)
+    }
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// Reads the status line and headers, informational responses are skipped
+async fn read_head<R: AsyncBufRead + Unpin>(reader: &mut R, limits: &Limits) -> StdResult<(u16, String, Headers), RequestError> {
+    let mut buf = Vec::new();
+    loop {
+        let (_, status_line) = read_line(reader, &mut buf, limits.max_request_line)
+            .await?
+            .ok_or(RequestError::Malformed("status line is too long"))?;
+        let mut parts = status_line.splitn(3, ' ');
+        let (Some(version), Some(status)) = (parts.next(), parts.next()) else {
+            return Err(RequestError::Malformed("invalid status line"));
+        };
+        if !version.starts_with("HTTP/1.") {
+            return Err(RequestError::Version(version.to_owned()));
+        }
+        let status = match status.parse::<u16>() {
+            Ok(code) if status.len() == 3 && (100..600).contains(&code) => code,
+            _ => return Err(RequestError::Malformed("invalid status code")),
+        };
+        let reason = parts.next().unwrap_or_default().to_owned();
+        let headers = read_headers(reader, &mut buf, limits).await?;
+        if !(100..200).contains(&status) {
+            return Ok((status, reason, headers));
+        }
+    }
+}
+
+/// The parts of an `http` or `https` url needed to send a request
+#[derive(Debug)]
+struct Url {
+    tls: bool,
+    /// host without the brackets of IPv6 addresses
+    host: String,
+    port: u16,
+    /// host and port as written, sent as `Host`
+    authority: String,
+    /// path and query
+    target: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> Result<Self> {
+        let invalid = || ClientError::Url(url.to_owned());
+        let (tls, rest) = match url.split_once("://") {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("https") => (true, rest),
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => (false, rest),
+            _ => return Err(invalid()),
+        };
+        let (authority, target) = match rest.find(['/', '?']) {
+            Some(i) if rest[i..].starts_with('?') => (&rest[..i], format!("/{}", &rest[i..])),
+            Some(i) => (&rest[..i], rest[i..].to_owned()),
+            None => (rest, "/".to_owned()),
+        };
+        // the fragment is never sent
+        let target = target.split('#').next().unwrap_or_default().to_owned();
+        let default_port = if tls { 443 } else { 80 };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port.parse().map_err(|_| invalid())?),
+            _ => (authority, default_port),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() || authority.contains('@') {
+            return Err(invalid());
+        }
+        Ok(Self {
+            tls,
+            host: host.to_owned(),
+            port,
+            authority: authority.to_owned(),
+            target,
+        })
+    }
+}
+
+/// Parses the parameters of a `Bearer` challenge, values may be quoted and contain commas
+fn parse_bearer_challenge(value: &str) -> Option<HashMap<String, String>> {
+    let (scheme, params) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    let mut parsed = HashMap::new();
+    let mut chars = params.chars().peekable();
+    loop {
+        while chars.next_if(|c| *c == ',' || c.is_whitespace()).is_some() {}
+        let name = std::iter::from_fn(|| chars.next_if(|c| *c != '=')).collect::<String>();
+        if name.is_empty() || chars.next() != Some('=') {
+            break;
+        }
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+        } else {
+            value = std::iter::from_fn(|| chars.next_if(|c| *c != ',')).collect::<String>();
+        }
+        parsed.insert(name.trim().to_ascii_lowercase(), value.trim().to_owned());
+    }
+    Some(parsed)
+}
+
+/// Percent-encodes a query value, keeping the characters used in scopes readable
+fn encode_query(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b if b.is_ascii_alphanumeric() || b"-_.~:/".contains(&b) => (b as char).to_string(),
+            b => format!("%{b:02X}"),
+        })
+        .collect()
+}
@@ -0,0 +1,181 @@
+use crate::body::{self, Body, BodyReader, Framing};
+use crate::encoding::ContentEncoding;
+use crate::request::{Limits, Request, RequestError};
+use crate::response::Response;
+use crate::status_code::StatusCode;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+/// Keep-alive settings and request limits of a connection
+#[derive(Debug, Clone)]
+pub struct ConnectionConfig {
+    /// whether the connection may serve more than one request
+    pub keep_alive: bool,
+    /// how long to wait for the next request before closing the connection
+    pub idle_timeout: Duration,
+    /// maximum number of requests served on a single connection
+    pub max_requests: usize,
+    /// hand request bodies to the handler as a [`BodyReader`] instead of reading them first
+    pub stream_bodies: bool,
+    pub limits: Limits,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            keep_alive: true,
+            idle_timeout: Duration::from_secs(5),
+            max_requests: 100,
+            stream_bodies: false,
+            limits: Limits::default(),
+        }
+    }
+}
+
+/// The stream handed back by a dropped [`Response`], `None` if the connection must be closed
+pub(crate) type Release = oneshot::Sender<Option<OwnedWriteHalf>>;
+
+/// An HTTP/1.1 connection serving requests one after the other.
+///
+/// The next request is parsed only after the [`Response`] of the previous one has been dropped,
+/// so pipelined requests are answered in order.
+pub struct Connection {
+    reader: Option<BufReader<OwnedReadHalf>>,
+    writer: Option<OwnedWriteHalf>,
+    pending_reader: Option<oneshot::Receiver<Option<BufReader<OwnedReadHalf>>>>,
+    pending_writer: Option<oneshot::Receiver<Option<OwnedWriteHalf>>>,
+    address: SocketAddr,
+    config: ConnectionConfig,
+    served: usize,
+    closed: bool,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream, address: SocketAddr, config: ConnectionConfig) -> Self {
+        let (reader, writer) = stream.into_split();
+        Self {
+            reader: Some(BufReader::new(reader)),
+            writer: Some(writer),
+            pending_reader: None,
+            pending_writer: None,
+            address,
+            config,
+            served: 0,
+            closed: false,
+        }
+    }
+
+    /// Waits for the next request, returns `None` once the connection is closed
+    pub async fn next(&mut self) -> Option<Result<(Request, Response), RequestError>> {
+        if self.closed {
+            return None;
+        }
+        let (Some(writer), Some(mut reader)) = self.reclaim().await else {
+            self.closed = true;
+            return None;
+        };
+
+        // an idle client or a closed socket ends the connection silently
+        match timeout(self.config.idle_timeout, reader.fill_buf()).await {
+            Ok(Ok(buf)) if !buf.is_empty() => {}
+            _ => {
+                self.closed = true;
+                return None;
+            }
+        }
+
+        let req = match self.read_request(reader).await {
+            Ok(req) => req,
+            Err(err) => {
+                self.closed = true;
+                if let Some(status) = err.status_code() {
+                    send_error(writer, status).await;
+                }
+                return Some(Err(err));
+            }
+        };
+
+        self.served += 1;
+        let keep_alive = self.config.keep_alive && self.served < self.config.max_requests && req.keep_alive();
+        self.closed = !keep_alive;
+
+        let (release, pending) = oneshot::channel();
+        self.pending_writer = Some(pending);
+        let mut res = Response::new(writer, release);
+        if req.version == "HTTP/1.0" {
+            res.disable_chunked();
+        }
+        if req.method == "HEAD" {
+            res.head_request();
+        }
+        res.accept_encoding(req.headers.get("Accept-Encoding").and_then(ContentEncoding::negotiate));
+        if keep_alive {
+            res.set_header("Connection", "keep-alive");
+            res.set_header(
+                "Keep-Alive",
+                &format!(
+                    "timeout={}, max={}",
+                    self.config.idle_timeout.as_secs(),
+                    self.config.max_requests - self.served
+                ),
+            );
+        } else {
+            res.set_header("Connection", "close");
+        }
+        Some(Ok((req, res)))
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Waits for the previous request and response to hand back the two halves of the stream
+    async fn reclaim(&mut self) -> (Option<OwnedWriteHalf>, Option<BufReader<OwnedReadHalf>>) {
+        if let Some(pending) = self.pending_writer.take() {
+            self.writer = pending.await.ok().flatten();
+        }
+        if let Some(pending) = self.pending_reader.take() {
+            self.reader = pending.await.ok().flatten();
+        }
+        (self.writer.take(), self.reader.take())
+    }
+
+    async fn read_request(&mut self, mut reader: BufReader<OwnedReadHalf>) -> Result<Request, RequestError> {
+        let limits = &self.config.limits;
+        let mut req = timeout(limits.header_timeout, Request::parse_head(&mut reader, self.address, limits))
+            .await
+            .map_err(|_| RequestError::Timeout)??;
+        let framing = Framing::of(&req)?;
+        let encoding = body::take_content_encoding(&mut req.headers)?;
+        match framing {
+            Some(framing) if self.config.stream_bodies => {
+                let (release, pending) = oneshot::channel();
+                self.pending_reader = Some(pending);
+                req.body = Body::Stream(BodyReader::new(reader, framing, encoding, limits.clone(), release));
+            }
+            Some(framing) => {
+                let body = timeout(limits.body_timeout, body::read_full(&mut reader, framing, encoding, limits))
+                    .await
+                    .map_err(|_| RequestError::Timeout)??;
+                req.body = Body::Full(body);
+                self.reader = Some(reader);
+            }
+            None => self.reader = Some(reader),
+        }
+        Ok(req)
+    }
+}
+
+/// Answers a request that could not be parsed, the connection is closed afterwards
+async fn send_error(writer: OwnedWriteHalf, status: StatusCode) {
+    let (release, _) = oneshot::channel();
+    let mut res = Response::new(writer, release);
+    let (status_code, status_text) = status.as_tuple();
+    res.set_header("Connection", "close");
+    let _ = res.status(status).try_send(format!("{status_code} {status_text}")).await;
+}
@@ -0,0 +1,179 @@
+use flate2::write::{GzDecoder, GzEncoder, ZlibDecoder, ZlibEncoder};
+use flate2::Compression;
+use std::io::{self, Write};
+
+/// Responses with a known length below this size are not worth compressing
+pub(crate) const MIN_COMPRESS_SIZE: u64 = 1024;
+
+/// A content coding supported for request and response bodies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Gzip,
+    /// zlib format, as specified for HTTP `deflate`
+    Deflate,
+}
+
+impl ContentEncoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate",
+        }
+    }
+
+    /// Parses a `Content-Encoding` value, `Ok(None)` for identity and `Err` for unsupported codings
+    pub fn from_header(value: &str) -> Result<Option<Self>, String> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "identity" => Ok(None),
+            "gzip" | "x-gzip" => Ok(Some(ContentEncoding::Gzip)),
+            "deflate" => Ok(Some(ContentEncoding::Deflate)),
+            _ => Err(value.to_owned()),
+        }
+    }
+
+    /// Picks the preferred coding accepted by an `Accept-Encoding` value
+    pub fn negotiate(accept_encoding: &str) -> Option<Self> {
+        let mut best: Option<(Self, f32)> = None;
+        for item in accept_encoding.split(',') {
+            let mut params = item.split(';');
+            let coding = params.next().unwrap_or("").trim().to_ascii_lowercase();
+            let quality = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let encoding = match coding.as_str() {
+                "gzip" | "x-gzip" | "*" => ContentEncoding::Gzip,
+                "deflate" => ContentEncoding::Deflate,
+                _ => continue,
+            };
+            // on equal quality the first listed coding wins
+            if quality > 0.0 && best.is_none_or(|(_, q)| quality > q) {
+                best = Some((encoding, quality));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+}
+
+/// Whether compressing the content type is likely to reduce its size,
+/// event streams are not since a compressor holds the events back until its buffer fills
+pub(crate) fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+    (mime.starts_with("text/") && !mime.eq_ignore_ascii_case("text/event-stream"))
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime,
+            "application/json" | "application/javascript" | "application/xml" | "application/xhtml+xml"
+        )
+}
+
+/// Inflates a body as its compressed bytes arrive
+pub(crate) enum Decoder {
+    Gzip(GzDecoder<Inflated>),
+    Deflate(ZlibDecoder<Inflated>),
+}
+
+impl Decoder {
+    /// A decoder failing with [`io::ErrorKind::FileTooLarge`] once more than `limit` bytes are inflated
+    pub(crate) fn new(encoding: ContentEncoding, limit: usize) -> Self {
+        let inflated = Inflated { buf: Vec::new(), remaining: limit };
+        match encoding {
+            ContentEncoding::Gzip => Decoder::Gzip(GzDecoder::new(inflated)),
+            ContentEncoding::Deflate => Decoder::Deflate(ZlibDecoder::new(inflated)),
+        }
+    }
+
+    /// Feeds compressed bytes, returns what could be inflated so far
+    pub(crate) fn write(&mut self, buf: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Decoder::Gzip(d) => {
+                d.write_all(buf)?;
+                Ok(std::mem::take(&mut d.get_mut().buf))
+            }
+            Decoder::Deflate(d) => {
+                d.write_all(buf)?;
+                Ok(std::mem::take(&mut d.get_mut().buf))
+            }
+        }
+    }
+
+    /// Returns the remaining bytes, fails if the compressed stream is truncated
+    pub(crate) fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Decoder::Gzip(d) => d.finish().map(|inflated| inflated.buf),
+            Decoder::Deflate(d) => d.finish().map(|inflated| inflated.buf),
+        }
+    }
+}
+
+/// The inflated bytes not yet taken, the decoder writes them in small pieces so the limit holds before they pile up
+pub(crate) struct Inflated {
+    buf: Vec<u8>,
+    remaining: usize,
+}
+
+impl Write for Inflated {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > self.remaining {
+            return Err(io::Error::new(io::ErrorKind::FileTooLarge, "inflated body is too large"));
+        }
+        self.remaining -= buf.len();
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Compresses a response body as it is written
+pub(crate) enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    pub(crate) fn new(encoding: ContentEncoding) -> Self {
+        match encoding {
+            ContentEncoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+            ContentEncoding::Deflate => Encoder::Deflate(ZlibEncoder::new(Vec::new(), Compression::default())),
+        }
+    }
+
+    /// Feeds body bytes, returns the compressed output produced so far
+    pub(crate) fn write(&mut self, buf: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(e) => {
+                e.write_all(buf)?;
+                Ok(std::mem::take(e.get_mut()))
+            }
+            Encoder::Deflate(e) => {
+                e.write_all(buf)?;
+                Ok(std::mem::take(e.get_mut()))
+            }
+        }
+    }
+
+    /// Forces out everything written so far, for streamed responses
+    pub(crate) fn flush(&mut self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(e) => {
+                e.flush()?;
+                Ok(std::mem::take(e.get_mut()))
+            }
+            Encoder::Deflate(e) => {
+                e.flush()?;
+                Ok(std::mem::take(e.get_mut()))
+            }
+        }
+    }
+
+    pub(crate) fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(e) => e.finish(),
+            Encoder::Deflate(e) => e.finish(),
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use crate::response::{BodyWriter, ResponseError, Sendable};
+use crate::{ContentType, Request, Response, StatusCode};
+use std::ffi::OsStr;
+use std::io::{self, SeekFrom};
+use std::path::Path;
+use std::result::Result as StdResult;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+type Result<T> = StdResult<T, ResponseError>;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The part of the file answered to the request
+#[derive(Debug, Clone, Copy)]
+enum Part {
+    Full,
+    /// first and last byte, both inclusive
+    Range(u64, u64),
+    NotModified,
+    Unsatisfiable,
+}
+
+/// A file sent in fixed size chunks, answering conditional and range requests.
+///
+/// [`Response::send_file`] also answers 404 when the file does not exist.
+#[derive(Debug)]
+pub struct StaticFile {
+    file: File,
+    content_type: String,
+    len: u64,
+    modified: Option<SystemTime>,
+    etag: String,
+    part: Part,
+}
+
+impl StaticFile {
+    /// Opens the file and evaluates the `If-None-Match`, `If-Modified-Since`, `Range` and `If-Range` headers.
+    ///
+    /// Fails with [`io::ErrorKind::NotFound`] for missing files and directories.
+    pub async fn open(path: &Path, req: &Request) -> io::Result<Self> {
+        let file = File::open(path).await?;
+        let metadata = file.metadata().await?;
+        if metadata.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "path is a directory"));
+        }
+        let len = metadata.len();
+        let modified = metadata.modified().ok();
+        let etag = format!("\"{:x}-{:x}\"", unix_secs(modified), len);
+        let ext = path.extension().unwrap_or(OsStr::new("")).to_str().unwrap_or("");
+
+        let mut file = Self {
+            file,
+            content_type: ContentType::from_ext(ext).to_string(),
+            len,
+            modified,
+            etag,
+            part: Part::Full,
+        };
+        if matches!(req.method.as_str(), "GET" | "HEAD") {
+            file.part = file.select_part(req);
+        }
+        Ok(file)
+    }
+
+    fn select_part(&self, req: &Request) -> Part {
+        // If-Modified-Since is ignored when If-None-Match is present
+        let not_modified = match (req.headers.get("If-None-Match"), req.headers.get("If-Modified-Since")) {
+            (Some(etags), _) => self.matches_etag(etags),
+            (None, Some(since)) => httpdate::parse_http_date(since).is_ok_and(|since| self.modified_before(since)),
+            (None, None) => false,
+        };
+        if not_modified {
+            return Part::NotModified;
+        }
+
+        let Some(range) = req.headers.get("Range") else {
+            return Part::Full;
+        };
+        // a range of an outdated representation is answered with the whole file
+        let current = req.headers.get("If-Range").is_none_or(|validator| match validator.trim().starts_with('"') {
+            true => validator.trim() == self.etag,
+            false => httpdate::parse_http_date(validator).is_ok_and(|date| unix_secs(Some(date)) == unix_secs(self.modified)),
+        });
+        match current {
+            true => parse_range(range, self.len),
+            false => Part::Full,
+        }
+    }
+
+    /// Weak comparison of the entity tags listed in `If-None-Match`
+    fn matches_etag(&self, etags: &str) -> bool {
+        etags
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == self.etag)
+    }
+
+    fn modified_before(&self, since: SystemTime) -> bool {
+        self.modified.is_some() && unix_secs(self.modified) <= unix_secs(Some(since))
+    }
+}
+
+/// Parses a single `bytes` range, other units and multiple ranges are answered with the whole file
+fn parse_range(range: &str, len: u64) -> Part {
+    let Some(spec) = range.trim().strip_prefix("bytes=") else {
+        return Part::Full;
+    };
+    if spec.contains(',') {
+        return Part::Full;
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return Part::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+
+    let bounds = match (start.is_empty(), end.is_empty()) {
+        // suffix range: the last `end` bytes
+        (true, false) => match end.parse::<u64>() {
+            Ok(0) => return Part::Unsatisfiable,
+            Ok(suffix) => (len.saturating_sub(suffix), len.saturating_sub(1)),
+            Err(_) => return Part::Full,
+        },
+        (false, _) => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+            (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+            _ => return Part::Full,
+        },
+        (true, true) => return Part::Full,
+    };
+    match bounds {
+        (start, _) if start >= len => Part::Unsatisfiable,
+        (start, end) => Part::Range(start, end),
+    }
+}
+
+fn unix_secs(time: Option<SystemTime>) -> u64 {
+    time.and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs())
+}
+
+impl Sendable for StaticFile {
+    fn prepare(&self, res: &mut Response) {
+        res.raw_content_type(&self.content_type);
+        res.set_header("Accept-Ranges", "bytes");
+        res.set_header("ETag", &self.etag);
+        if let Some(modified) = self.modified {
+            res.set_header("Last-Modified", &httpdate::fmt_http_date(modified));
+        }
+        match self.part {
+            Part::Full => {}
+            Part::Range(start, end) => {
+                res.status(StatusCode::PartialContent);
+                res.set_header("Content-Range", &format!("bytes {start}-{end}/{}", self.len));
+            }
+            Part::NotModified => {
+                res.status(StatusCode::NotModified);
+            }
+            Part::Unsatisfiable => {
+                res.status(StatusCode::RangeNotSatisfiable);
+                res.set_header("Content-Range", &format!("bytes */{}", self.len));
+            }
+        }
+    }
+
+    async fn write(mut self, body: &mut BodyWriter<'_>) -> Result<()> {
+        let (start, mut remaining) = match self.part {
+            Part::Full => (0, self.len),
+            Part::Range(start, end) => (start, end - start + 1),
+            Part::NotModified | Part::Unsatisfiable => return Ok(()),
+        };
+        self.file.seek(SeekFrom::Start(start)).await.map_err(ResponseError::sendable)?;
+        let mut buf = vec![0_u8; CHUNK_SIZE];
+        while remaining > 0 {
+            let max = remaining.min(CHUNK_SIZE as u64) as usize;
+            let read = self.file.read(&mut buf[..max]).await.map_err(ResponseError::sendable)?;
+            if read == 0 {
+                return Err(ResponseError::Sendable("file was truncated while sending".to_owned()));
+            }
+            body.write_all(&buf[..read]).await?;
+            remaining -= read as u64;
+        }
+        Ok(())
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        match self.part {
+            Part::Full => Some(self.len),
+            Part::Range(start, end) => Some(end - start + 1),
+            Part::NotModified | Part::Unsatisfiable => Some(0),
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use crate::middleware::RequestId;
+use crate::{metrics::Metrics, utils::accept_connection, ConnectionConfig, Request, Response};
+use std::any::Any;
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::net::TcpListener;
+use tokio::task;
+
+/// Answers a request.
+///
+/// The handler must send the response, an unsent response closes the connection.
+pub trait Handler: Send + Sync + 'static {
+    fn call(&self, req: Request, res: &mut Response) -> impl Future<Output = ()> + Send;
+
+    /// Wraps the handler in a middleware, the last added layer runs first
+    fn layer<M: Middleware>(self, middleware: M) -> Layered<M, Self>
+    where
+        Self: Sized,
+    {
+        Layered { middleware, inner: self }
+    }
+}
+
+/// Runs around a handler, it can change the request, answer on its own
+/// or call `next` and look at the response afterwards
+pub trait Middleware: Send + Sync + 'static {
+    fn call<H: Handler>(&self, req: Request, res: &mut Response, next: &H) -> impl Future<Output = ()> + Send;
+}
+
+/// An optional layer, `None` calls the handler directly
+impl<M: Middleware> Middleware for Option<M> {
+    async fn call<H: Handler>(&self, req: Request, res: &mut Response, next: &H) {
+        match self {
+            Some(middleware) => middleware.call(req, res, next).await,
+            None => next.call(req, res).await,
+        }
+    }
+}
+
+/// A handler wrapped in a middleware, see [`Handler::layer`]
+pub struct Layered<M, H> {
+    middleware: M,
+    inner: H,
+}
+
+impl<M: Middleware, H: Handler> Handler for Layered<M, H> {
+    fn call(&self, req: Request, res: &mut Response) -> impl Future<Output = ()> + Send {
+        self.middleware.call(req, res, &self.inner)
+    }
+}
+
+/// Accepts connections forever and answers their requests with the handler.
+///
+/// A panicking handler is answered with 500, the server keeps running.
+pub async fn serve<H: Handler>(listener: TcpListener, handler: H) {
+    serve_with(listener, ConnectionConfig::default(), handler).await
+}
+
+/// Same as [`serve`] with custom keep-alive settings and limits
+pub async fn serve_with<H: Handler>(listener: TcpListener, config: ConnectionConfig, handler: H) {
+    let handler = Arc::new(handler);
+    loop {
+        let mut conn = match accept_connection(&listener, &config).await {
+            Ok(conn) => conn,
+            Err(err) => {
+                eprintln!("{err}");
+                continue;
+            }
+        };
+        let handler = handler.clone();
+        task::spawn(async move {
+            while let Some(Ok((req, mut res))) = conn.next().await {
+                Metrics::global().count_request();
+                call_catching_panics(handler.as_ref(), req, &mut res).await;
+            }
+        });
+    }
+}
+
+/// Calls the handler, a panic is logged with the request id and answered with 500
+pub(crate) async fn call_catching_panics<H: Handler>(handler: &H, req: Request, res: &mut Response) {
+    let (method, path) = (req.method.clone(), req.path.clone());
+    let client_id = req.headers.get(RequestId::HEADER).map(str::to_owned);
+    let Err(panic) = CatchUnwind(Box::pin(handler.call(req, res))).await else {
+        return;
+    };
+    Metrics::global().count_panic();
+    // the id given by a `RequestId` layer, or the one sent by the client
+    let id = res.header(RequestId::HEADER).or(client_id.as_deref()).unwrap_or("-").to_owned();
+    eprintln!("request {id} ({method} {path}) panicked: {}", panic_message(&*panic));
+    res.send_server_error().await;
+}
+
+/// Resolves to `Err` with the payload if polling the future panics
+struct CatchUnwind<F>(Pin<Box<F>>);
+
+impl<F: Future> Future for CatchUnwind<F> {
+    type Output = Result<F::Output, Box<dyn Any + Send>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let future = self.0.as_mut();
+        match catch_unwind(AssertUnwindSafe(|| future.poll(cx))) {
+            Ok(Poll::Pending) => Poll::Pending,
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Err(panic) => Poll::Ready(Err(panic)),
+        }
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic.downcast_ref::<String>().map_or("unknown panic", String::as_str),
+    }
+}
@@ -0,0 +1,77 @@
+use crate::response::{BodyWriter, ResponseError, Sendable};
+use crate::{ContentType, Response, StatusCode};
+use serde::Serialize;
+use std::io;
+use std::result::Result as StdResult;
+use thiserror::Error;
+
+/// Serializes the value as the JSON body of the response.
+///
+/// The value is serialized once to compute `Content-Length` and again while writing,
+/// a value that can't be serialized closes the connection.
+pub struct Json<T>(pub T);
+
+impl<T: Serialize> Sendable for Json<T> {
+    fn prepare(&self, res: &mut Response) {
+        res.content_type(ContentType::Json);
+    }
+
+    async fn write(self, body: &mut BodyWriter<'_>) -> StdResult<(), ResponseError> {
+        let bytes = serde_json::to_vec(&self.0).map_err(ResponseError::sendable)?;
+        body.write_all(&bytes).await
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        let mut counter = ByteCounter(0);
+        serde_json::to_writer(&mut counter, &self.0).ok().map(|_| counter.0)
+    }
+}
+
+struct ByteCounter(u64);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Why a JSON request body was rejected, it can be sent as the `{"error": ...}` response
+#[derive(Error, Debug)]
+pub enum JsonError {
+    #[error("request body is larger than {0} bytes")]
+    TooLarge(usize),
+    #[error("invalid JSON body: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("request body was not read")]
+    Unread,
+}
+
+impl JsonError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            JsonError::TooLarge(_) => StatusCode::PayloadTooLarge,
+            JsonError::Parse(_) => StatusCode::BadRequest,
+            // streamed bodies must be read with `Request::read_body` first
+            JsonError::Unread => StatusCode::InternalServerError,
+        }
+    }
+}
+
+impl Sendable for JsonError {
+    fn prepare(&self, res: &mut Response) {
+        res.status(self.status_code()).content_type(ContentType::Json);
+    }
+
+    async fn write(self, body: &mut BodyWriter<'_>) -> StdResult<(), ResponseError> {
+        Json(serde_json::json!({ "error": self.to_string() })).write(body).await
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        Json(serde_json::json!({ "error": self.to_string() })).content_length()
+    }
+}
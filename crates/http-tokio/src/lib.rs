@@ -1,10 +1,35 @@
+mod body;
+#[cfg(feature = "client")]
+mod client;
+mod connection;
 mod content_type;
+mod encoding;
+mod file;
+mod handler;
+mod headers;
+#[cfg(feature = "serde")]
+mod json;
+mod metrics;
+pub mod middleware;
 mod request;
 mod response;
+mod sse;
 mod status_code;
 pub mod utils;
 
+pub use body::{Body, BodyReader};
+#[cfg(feature = "client")]
+pub use client::{Client, ClientError, ClientRequest, ClientResponse};
+pub use connection::{Connection, ConnectionConfig};
 pub use content_type::ContentType;
-pub use request::{Request, RequestError};
-pub use response::{Response, ResponseError, Sendable};
+pub use encoding::ContentEncoding;
+pub use file::StaticFile;
+pub use handler::{serve, serve_with, Handler, Layered, Middleware};
+pub use headers::Headers;
+#[cfg(feature = "serde")]
+pub use json::{Json, JsonError};
+pub use metrics::Metrics;
+pub use request::{Limits, Request, RequestError};
+pub use response::{BodyWriter, Response, ResponseError, Sendable, Streaming};
+pub use sse::{EventStream, ServerEvent};
 pub use status_code::StatusCode;
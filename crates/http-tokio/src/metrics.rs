@@ -0,0 +1,41 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static METRICS: Metrics = Metrics::new();
+
+/// Counters of the requests answered by [`serve`](crate::serve)
+#[derive(Debug)]
+pub struct Metrics {
+    requests: AtomicU64,
+    panics: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            requests: AtomicU64::new(0),
+            panics: AtomicU64::new(0),
+        }
+    }
+
+    pub fn global() -> &'static Metrics {
+        &METRICS
+    }
+
+    /// Requests handed to a handler
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// Handler panics answered with 500
+    pub fn panics(&self) -> u64 {
+        self.panics.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn count_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_panic(&self) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,262 @@
+//! Layers for [`Handler::layer`](crate::Handler::layer)
+
+use crate::handler::{call_catching_panics, Handler, Middleware};
+use crate::utils::fmt_now;
+use crate::{Request, Response, StatusCode};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Prints a line for each request with its status and duration
+pub struct Logger;
+
+impl Middleware for Logger {
+    async fn call<H: Handler>(&self, req: Request, res: &mut Response, next: &H) {
+        let method = format!("[\x1b[96;1m{}\x1b[0m]", req.method);
+        let path = req.path.clone();
+        let start = Instant::now();
+        next.call(req, res).await;
+
+        let (status_code, _) = res.status_code().as_tuple();
+        let elapsed = start.elapsed().as_millis();
+        println!("\x1b[2m{}\x1b[0m {: <19} {} {status_code} {elapsed}ms", fmt_now(), method, path);
+    }
+}
+
+/// Answers 401 to the requests rejected by the predicate
+pub struct Auth<F> {
+    authorized: F,
+}
+
+impl<F: Fn(&Request) -> bool + Send + Sync + 'static> Auth<F> {
+    pub fn new(authorized: F) -> Self {
+        Self { authorized }
+    }
+}
+
+impl Auth<Box<dyn Fn(&Request) -> bool + Send + Sync>> {
+    /// Accepts the requests with the `Authorization: Bearer <token>` header
+    pub fn bearer(token: &str) -> Self {
+        let expected = format!("Bearer {token}");
+        Self::new(Box::new(move |req| req.headers.get("Authorization") == Some(expected.as_str())))
+    }
+}
+
+impl<F: Fn(&Request) -> bool + Send + Sync + 'static> Middleware for Auth<F> {
+    async fn call<H: Handler>(&self, req: Request, res: &mut Response, next: &H) {
+        if (self.authorized)(&req) {
+            next.call(req, res).await;
+        } else {
+            let _ = res.status(StatusCode::Unauthorized).try_send("401 Unauthorized").await;
+        }
+    }
+}
+
+/// Cross-origin resource sharing, answers the preflight requests and sets the CORS headers
+/// of the requests coming from an allowed origin.
+///
+/// Origins are matched exactly, `*` allows any origin and a pattern like `https://*.example.com`
+/// matches any subdomain.
+pub struct Cors {
+    origins: Vec<String>,
+    methods: Vec<String>,
+    headers: Vec<String>,
+    expose_headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Cors {
+    pub fn new<S: Into<String>>(allowed_origins: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            origins: allowed_origins.into_iter().map(Into::into).collect(),
+            methods: ["GET", "HEAD", "POST"].map(String::from).to_vec(),
+            headers: Vec::new(),
+            expose_headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    /// Methods allowed in preflight requests, defaults to GET, HEAD and POST
+    pub fn allow_methods<S: Into<String>>(mut self, methods: impl IntoIterator<Item = S>) -> Self {
+        self.methods = methods.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Request headers allowed in preflight requests, `*` allows the requested ones
+    pub fn allow_headers<S: Into<String>>(mut self, headers: impl IntoIterator<Item = S>) -> Self {
+        self.headers = headers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Response headers readable by the browser besides the safelisted ones
+    pub fn expose_headers<S: Into<String>>(mut self, headers: impl IntoIterator<Item = S>) -> Self {
+        self.expose_headers = headers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Allows cookies and `Authorization` headers, the origin is then always sent back instead of `*`
+    pub fn allow_credentials(mut self, credentials: bool) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// How long the browser can cache a preflight response
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.origins.iter().any(|pattern| pattern == "*" || matches_pattern(pattern, origin))
+    }
+
+    fn set_origin(&self, origin: &str, res: &mut Response) {
+        if self.credentials || !self.origins.iter().any(|o| o == "*") {
+            res.set_header("Access-Control-Allow-Origin", origin);
+            res.set_header("Vary", "Origin"); // for caching
+        } else {
+            res.set_header("Access-Control-Allow-Origin", "*");
+        }
+        if self.credentials {
+            res.set_header("Access-Control-Allow-Credentials", "true");
+        }
+    }
+
+    async fn preflight(&self, req: &Request, res: &mut Response) {
+        let origin = req.headers.get("Origin").unwrap_or_default();
+        if self.allows(origin) {
+            self.set_origin(origin, res);
+            res.set_header("Vary", "Origin, Access-Control-Request-Method, Access-Control-Request-Headers");
+            res.set_header("Access-Control-Allow-Methods", &self.methods.join(", "));
+            let headers = match self.headers.iter().any(|h| h == "*") {
+                true => req.headers.get_joined("Access-Control-Request-Headers").unwrap_or_default(),
+                false => self.headers.join(", "),
+            };
+            if !headers.is_empty() {
+                res.set_header("Access-Control-Allow-Headers", &headers);
+            }
+            if let Some(max_age) = self.max_age {
+                res.set_header("Access-Control-Max-Age", &max_age.as_secs().to_string());
+            }
+        }
+        // a disallowed origin gets no CORS headers, the browser then blocks the request
+        let _ = res.status(StatusCode::NoContent).try_send_empty().await;
+    }
+}
+
+impl Middleware for Cors {
+    async fn call<H: Handler>(&self, req: Request, res: &mut Response, next: &H) {
+        let Some(origin) = req.headers.get("Origin") else {
+            return next.call(req, res).await;
+        };
+        if req.method == "OPTIONS" && req.headers.contains("Access-Control-Request-Method") {
+            return self.preflight(&req, res).await;
+        }
+        if self.allows(origin) {
+            self.set_origin(origin, res);
+            if !self.expose_headers.is_empty() {
+                res.set_header("Access-Control-Expose-Headers", &self.expose_headers.join(", "));
+            }
+        }
+        next.call(req, res).await;
+    }
+}
+
+/// Matches an origin against a pattern where each `*` stands for any characters but `/`
+fn matches_pattern(pattern: &str, origin: &str) -> bool {
+    let Some((prefix, rest)) = pattern.split_once('*') else {
+        return pattern.eq_ignore_ascii_case(origin);
+    };
+    match origin.get(..prefix.len()) {
+        Some(start) if start.eq_ignore_ascii_case(prefix) => {}
+        _ => return false,
+    }
+    let origin = &origin[prefix.len()..];
+    // try every split of the wildcard, origins are short
+    (0..=origin.len())
+        .filter(|&i| origin.is_char_boundary(i))
+        .take_while(|&i| !origin[..i].contains('/'))
+        .any(|i| matches_pattern(rest, &origin[i..]))
+}
+
+/// Gives each request an `X-Request-Id` header, kept from the client if it sent a valid one,
+/// the id is also sent back in the response
+pub struct RequestId {
+    prefix: String,
+    next: AtomicU64,
+}
+
+impl RequestId {
+    pub const HEADER: &'static str = "X-Request-Id";
+    const MAX_LEN: usize = 64;
+
+    pub fn new() -> Self {
+        let started = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Self {
+            prefix: format!("{started:x}"),
+            next: AtomicU64::new(1),
+        }
+    }
+
+    fn generate(&self) -> String {
+        format!("{}-{:06x}", self.prefix, self.next.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Middleware for RequestId {
+    async fn call<H: Handler>(&self, mut req: Request, res: &mut Response, next: &H) {
+        let valid = |id: &&str| {
+            !id.is_empty() && id.len() <= Self::MAX_LEN && id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b))
+        };
+        let id = match req.headers.get(Self::HEADER).filter(valid) {
+            Some(id) => id.to_owned(),
+            None => self.generate(),
+        };
+        req.headers.insert(Self::HEADER, &id);
+        res.set_header(Self::HEADER, &id);
+        next.call(req, res).await;
+    }
+}
+
+/// Answers 503 when the handler takes longer than the duration
+pub struct Timeout {
+    duration: Duration,
+}
+
+impl Timeout {
+    pub fn new(duration: Duration) -> Self {
+        Self { duration }
+    }
+}
+
+impl Middleware for Timeout {
+    async fn call<H: Handler>(&self, req: Request, res: &mut Response, next: &H) {
+        let (method, path) = (req.method.clone(), req.path.clone());
+        if tokio::time::timeout(self.duration, next.call(req, res)).await.is_err() {
+            eprintln!("{method} {path} timed out after {}s", self.duration.as_secs_f32());
+            // a partially written response can't be fixed, the connection is closed instead
+            if !res.started() {
+                let _ = res.status(StatusCode::ServiceUnavailable).try_send("503 ServiceUnavailable").await;
+            }
+        }
+    }
+}
+
+/// Answers 500 when the handler panics instead of dropping the connection,
+/// [`serve`](crate::serve) already does it around the whole handler
+pub struct CatchPanic;
+
+impl Middleware for CatchPanic {
+    async fn call<H: Handler>(&self, req: Request, res: &mut Response, next: &H) {
+        call_catching_panics(next, req, res).await;
+    }
+}
@@ -1,9 +1,17 @@
+use crate::{
+    body::{self, Body, BodyReader, Framing},
+    headers::Headers,
+    status_code::StatusCode,
+};
+#[cfg(feature = "serde")]
+use crate::json::JsonError;
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::result::Result as StdResult;
+use std::time::Duration;
 use thiserror::Error;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+use tokio::time::timeout;
 
 #[derive(Error, Debug)]
 pub enum RequestError {
@@ -11,99 +19,498 @@ pub enum RequestError {
     Read(#[from] tokio::io::Error),
     #[error("invalid Content-Length header `{0}`")]
     ContentLength(String),
-    #[error("request body is non utf-8")]
+    #[error("request is non utf-8")]
     Utf8(#[from] std::string::FromUtf8Error),
+    #[error("request has a body but no Content-Length")]
+    LengthRequired,
+    #[error("both Content-Length and Transfer-Encoding are set")]
+    AmbiguousLength,
+    #[error("unsupported Transfer-Encoding `{0}`")]
+    TransferEncoding(String),
+    #[error("malformed chunked body: {0}")]
+    Chunk(&'static str),
+    #[error("request line is too long")]
+    RequestLineTooLong,
+    #[error("request headers are too large")]
+    HeadersTooLarge,
+    #[error("request body is too large")]
+    BodyTooLarge,
+    #[error("timed out reading the request")]
+    Timeout,
+    #[error("malformed request: {0}")]
+    Malformed(&'static str),
+    #[error("unsupported HTTP version `{0}`")]
+    Version(String),
+    #[error("unsupported Content-Encoding `{0}`")]
+    ContentEncoding(String),
+    #[error("could not decode the request body: {0}")]
+    Decode(std::io::Error),
+}
+
+impl RequestError {
+    /// The status code to answer with, `None` when the client can't be answered anymore
+    pub fn status_code(&self) -> Option<StatusCode> {
+        match self {
+            RequestError::Read(_) => None,
+            RequestError::LengthRequired => Some(StatusCode::LengthRequired),
+            RequestError::TransferEncoding(_) => Some(StatusCode::NotImplemented),
+            RequestError::RequestLineTooLong => Some(StatusCode::URITooLong),
+            RequestError::HeadersTooLarge => Some(StatusCode::RequestHeaderFieldsTooLarge),
+            RequestError::BodyTooLarge => Some(StatusCode::PayloadTooLarge),
+            RequestError::Timeout => Some(StatusCode::RequestTimeout),
+            RequestError::Version(_) => Some(StatusCode::HTTPVersionNotSupported),
+            RequestError::ContentEncoding(_) => Some(StatusCode::UnsupportedMediaType),
+            RequestError::ContentLength(_)
+            | RequestError::Utf8(_)
+            | RequestError::AmbiguousLength
+            | RequestError::Chunk(_)
+            | RequestError::Malformed(_)
+            | RequestError::Decode(_) => Some(StatusCode::BadRequest),
+        }
+    }
 }
 
 type Result<T> = StdResult<T, RequestError>;
 
-// TODO: query params
+/// Bounds on what a client may send, checked while reading the request
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// maximum length of the request line, answered with `414`
+    pub max_request_line: usize,
+    /// maximum number of header fields, answered with `431`
+    pub max_headers: usize,
+    /// maximum size of the whole header section, answered with `431`
+    pub max_header_size: usize,
+    /// maximum size of the body, answered with `413`
+    pub max_body_size: usize,
+    /// time allowed to receive the request line and headers, answered with `408`
+    pub header_timeout: Duration,
+    /// time allowed to receive the body, answered with `408`
+    pub body_timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_request_line: 8 * 1024,
+            max_headers: 100,
+            max_header_size: 16 * 1024,
+            max_body_size: 1024 * 1024,
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(30),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Request {
     pub method: String,
+    /// percent-decoded path, without trailing slash
     pub path: String,
-    pub headers: HashMap<String, String>,
+    pub version: String,
+    pub headers: Headers,
     pub cookies: HashMap<String, String>,
-    pub body: String,
+    /// percent-decoded query parameters, a name can have more than one value
+    pub query: HashMap<String, Vec<String>>,
+    pub body: Body,
     pub address: SocketAddr,
 }
 
 impl Request {
+    /// The first value of the header, names are case-insensitive
     pub fn header(&self, key: &str) -> Option<String> {
         self.headers.get(key).map(|v| v.to_owned())
     }
     pub fn cookie(&self, name: &str) -> Option<&String> {
         self.cookies.get(name)
     }
+    /// The first value of the query parameter
+    pub fn query(&self, name: &str) -> Option<&String> {
+        self.query.get(name).and_then(|values| values.first())
+    }
+    /// The body bytes, empty while the body is still streamed
+    pub fn bytes(&self) -> &[u8] {
+        match &self.body {
+            Body::Full(bytes) => bytes,
+            Body::Stream(_) => &[],
+        }
+    }
+    /// The body as text, decoded on each call
+    pub fn text(&self) -> StdResult<&str, std::str::Utf8Error> {
+        std::str::from_utf8(self.bytes())
+    }
+    /// Deserializes the JSON body, a rejected body can be sent back with `res.send(err)`
+    #[cfg(feature = "serde")]
+    pub fn json<T: serde::de::DeserializeOwned>(&self) -> StdResult<T, JsonError> {
+        self.json_limited(usize::MAX)
+    }
+    /// Deserializes the JSON body, rejecting bodies larger than `limit` bytes
+    #[cfg(feature = "serde")]
+    pub fn json_limited<T: serde::de::DeserializeOwned>(&self, limit: usize) -> StdResult<T, JsonError> {
+        match &self.body {
+            Body::Stream(_) => Err(JsonError::Unread),
+            _ if self.bytes().len() > limit => Err(JsonError::TooLarge(limit)),
+            _ => Ok(serde_json::from_slice(self.bytes())?),
+        }
+    }
+    /// Reads a streamed body into memory within the size limit, see [`Request::bytes`]
+    pub async fn read_body(&mut self) -> Result<&[u8]> {
+        if let Body::Stream(reader) = &mut self.body {
+            self.body = Body::Full(reader.read_to_end().await?);
+        }
+        Ok(self.bytes())
+    }
+    /// Takes the body to read it as a stream, `None` if it was already read
+    pub fn body_reader(&mut self) -> Option<BodyReader> {
+        match std::mem::take(&mut self.body) {
+            Body::Stream(reader) => Some(reader),
+            body => {
+                self.body = body;
+                None
+            }
+        }
+    }
     pub fn matcher(&self) -> (&str, &str) {
         (self.method.as_str(), self.path.as_str())
     }
+    /// Whether the client asked to reuse the connection for further requests
+    pub fn keep_alive(&self) -> bool {
+        let has_option = |option: &str| {
+            self.headers
+                .get_all("Connection")
+                .flat_map(|v| v.split(','))
+                .any(|v| v.trim().eq_ignore_ascii_case(option))
+        };
+        match self.version.as_str() {
+            "HTTP/1.0" => has_option("keep-alive"),
+            _ => !has_option("close"),
+        }
+    }
 }
 
 impl Request {
-    pub async fn parse(value: (&mut TcpStream, SocketAddr)) -> Result<Self> {
-        let (stream, address) = value;
-        let mut buf = String::new();
-        let mut buf_reader = BufReader::new(stream);
+    /// Reads a single request, leaving any pipelined bytes in the reader
+    pub async fn parse<R: AsyncBufRead + Unpin>(buf_reader: &mut R, address: SocketAddr, limits: &Limits) -> Result<Self> {
+        let mut req = timeout(limits.header_timeout, Self::parse_head(buf_reader, address, limits))
+            .await
+            .map_err(|_| RequestError::Timeout)??;
+        if let Some(framing) = Framing::of(&req)? {
+            let encoding = body::take_content_encoding(&mut req.headers)?;
+            let body = timeout(limits.body_timeout, body::read_full(buf_reader, framing, encoding, limits))
+                .await
+                .map_err(|_| RequestError::Timeout)??;
+            req.body = Body::Full(body);
+        }
+        Ok(req)
+    }
 
-        // parsing status line
-        let (_, first_line) = read_line(&mut buf_reader, &mut buf).await?;
-        let mut status = first_line.split(" ");
-        let method = status.next().unwrap_or("GET").to_owned();
-        let full_path = status.next().unwrap_or("/").to_owned();
-        let mut full_path = full_path.split("?");
+    /// Reads the request line and headers, the body is left in the reader
+    pub(crate) async fn parse_head<R: AsyncBufRead + Unpin>(buf_reader: &mut R, address: SocketAddr, limits: &Limits) -> Result<Self> {
+        let mut buf = Vec::new();
 
-        let path = "/".to_owned() + full_path.next().unwrap_or("/").trim_end_matches("/").trim_start_matches("/");
-        let _query_string = full_path.next().unwrap_or("");
+        // parsing request line
+        let (_, request_line) = read_line(buf_reader, &mut buf, limits.max_request_line)
+            .await?
+            .ok_or(RequestError::RequestLineTooLong)?;
+        let mut parts = request_line.split(' ');
+        let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+            return Err(RequestError::Malformed("invalid request line"));
+        };
+        if !is_token(method) {
+            return Err(RequestError::Malformed("invalid method"));
+        }
+        match version {
+            "HTTP/1.1" | "HTTP/1.0" => {}
+            v if v.starts_with("HTTP/") => return Err(RequestError::Version(v.into())),
+            _ => return Err(RequestError::Malformed("invalid HTTP version")),
+        }
+        let (path, query) = parse_target(method, target)?;
 
         // parsing headers
-        let mut headers: HashMap<String, String> = HashMap::new();
-        loop {
-            let (len, line) = read_line(&mut buf_reader, &mut buf).await?;
-            if len <= 2 {
-                break;
-            }
-            if let Some((k, v)) = line.split_once(": ") {
-                headers.insert(k.to_owned(), v.to_owned());
-            }
+        let headers = read_headers(buf_reader, &mut buf, limits).await?;
+        if version == "HTTP/1.1" && headers.get_all("Host").count() != 1 {
+            return Err(RequestError::Malformed("HTTP/1.1 requires exactly one Host header"));
         }
 
         // parsing cookies
         let mut cookies = HashMap::<String, String>::new();
-        if let Some(cookie) = headers.get("Cookie") {
-            let split = cookie.split("; ");
-            for cookie in split {
-                let Some((k, v)) = cookie.split_once("=") else { continue };
-                cookies.insert(k.to_owned(), v.to_owned());
-            }
-        }
-
-        // parsing body
-        let mut body: String = "".to_owned();
-        if let Some(len) = headers.get("Content-Length") {
-            let len = str::parse::<usize>(len).map_err(|_| RequestError::ContentLength(len.into()))?;
-
-            let mut buf = vec![0 as u8; len];
-            buf_reader.read_exact(&mut buf).await?;
-
-            body = String::from_utf8(buf)?;
+        for cookie in headers.get_all("Cookie").flat_map(|v| v.split(';')) {
+            let Some((k, v)) = cookie.trim().split_once("=") else { continue };
+            cookies.insert(k.to_owned(), v.to_owned());
         }
 
         Ok(Self {
             path,
-            method,
+            method: method.to_owned(),
+            version: version.to_owned(),
             headers,
-            body,
+            body: Body::default(),
             address,
             cookies,
+            query,
         })
     }
 }
 
-async fn read_line(buf_reader: &mut BufReader<&mut TcpStream>, buf: &mut String) -> Result<(usize, String)> {
-    let len = buf_reader.read_line(buf).await?;
-    let parsed = String::from_utf8(buf.clone().into())?.replace("\r\n", ""); // remove line terminators
+/// Reads the header fields up to the empty line ending the head
+pub(crate) async fn read_headers<R: AsyncBufRead + Unpin>(buf_reader: &mut R, buf: &mut Vec<u8>, limits: &Limits) -> Result<Headers> {
+    let mut headers = Headers::new();
+    let mut header_size = 0;
+    loop {
+        let (len, line) = read_line(buf_reader, buf, limits.max_header_size - header_size)
+            .await?
+            .ok_or(RequestError::HeadersTooLarge)?;
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        header_size += len;
+        if headers.len() == limits.max_headers {
+            return Err(RequestError::HeadersTooLarge);
+        }
+        // obsolete line folding is rejected
+        if line.starts_with([' ', '\t']) {
+            return Err(RequestError::Malformed("folded header"));
+        }
+        let Some((k, v)) = line.split_once(':') else {
+            return Err(RequestError::Malformed("header without colon"));
+        };
+        if !is_token(k) {
+            return Err(RequestError::Malformed("invalid header name"));
+        }
+        headers.append(k, v.trim_matches([' ', '\t']));
+    }
+}
+
+type Query = HashMap<String, Vec<String>>;
+
+/// Splits the request target in decoded path and query parameters
+fn parse_target(method: &str, target: &str) -> Result<(String, Query)> {
+    let target = match target {
+        "*" if method == "OPTIONS" => "/",
+        // absolute-form, as sent to proxies
+        t if t.starts_with("http://") || t.starts_with("https://") => {
+            let without_scheme = &t[t.find("//").unwrap_or(0) + 2..];
+            without_scheme.find('/').map_or("/", |i| &without_scheme[i..])
+        }
+        t if t.starts_with('/') => t,
+        _ => return Err(RequestError::Malformed("invalid request target")),
+    };
+    let (path, query_string) = target.split_once('?').unwrap_or((target, ""));
+    let path = percent_decode(path, false)?;
+    let path = "/".to_owned() + path.trim_end_matches("/").trim_start_matches("/");
+
+    let mut query = Query::new();
+    for pair in query_string.split('&').filter(|p| !p.is_empty()) {
+        let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+        query
+            .entry(percent_decode(k, true)?)
+            .or_default()
+            .push(percent_decode(v, true)?);
+    }
+    Ok((path, query))
+}
+
+/// Decodes `%XX` sequences, and `+` as space in query components
+fn percent_decode(input: &str, plus_as_space: bool) -> Result<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+                let byte = hex
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                    .ok_or(RequestError::Malformed("invalid percent encoding"))?;
+                decoded.push(byte);
+                i += 3;
+            }
+            b'+' if plus_as_space => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| RequestError::Malformed("path is not utf-8"))
+}
+
+/// [RFC 9110: tokens](https://www.rfc-editor.org/rfc/rfc9110#name-tokens)
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Reads a line of at most `max` bytes, `None` if the line is longer
+pub(crate) async fn read_line<R: AsyncBufRead + Unpin>(buf_reader: &mut R, buf: &mut Vec<u8>, max: usize) -> Result<Option<(usize, String)>> {
     buf.clear();
-    return Ok((len, parsed));
+    loop {
+        let available = buf_reader.fill_buf().await?;
+        if available.is_empty() {
+            break;
+        }
+        let (used, done) = match available.iter().position(|b| *b == b'\n') {
+            Some(pos) => (pos + 1, true),
+            None => (available.len(), false),
+        };
+        if buf.len() + used > max {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&available[..used]);
+        buf_reader.consume(used);
+        if done {
+            break;
+        }
+    }
+    let len = buf.len();
+    let mut parsed = String::from_utf8(std::mem::take(buf))?;
+    // remove line terminators, a bare LF is tolerated
+    if parsed.ends_with('\n') {
+        parsed.pop();
+        if parsed.ends_with('\r') {
+            parsed.pop();
+        }
+    }
+    Ok(Some((len, parsed)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(input: &[u8]) -> Result<Request> {
+        parse_limited(input, &Limits::default()).await
+    }
+
+    async fn parse_limited(mut input: &[u8], limits: &Limits) -> Result<Request> {
+        Request::parse(&mut input, "127.0.0.1:4000".parse().unwrap(), limits).await
+    }
+
+    #[tokio::test]
+    async fn chunks_are_joined_and_extensions_ignored() {
+        let req = parse(b"POST /hook HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\n5;name=value\r\nhello\r\n6 ; other\r\n world\r\n0\r\nExpires: never\r\n\r\n").await;
+        assert_eq!(req.unwrap().bytes(), b"hello world");
+    }
+
+    #[tokio::test]
+    async fn malformed_chunks_are_rejected() {
+        for body in ["zz\r\nhello\r\n0\r\n\r\n", "\r\nhello\r\n0\r\n\r\n", "5\r\nhello!\r\n0\r\n\r\n"] {
+            let req = parse(format!("POST /hook HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\n{body}").as_bytes()).await;
+            assert!(matches!(req, Err(RequestError::Chunk(_))), "{req:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn transfer_encoding_with_content_length_is_ambiguous() {
+        let req = parse(b"POST /hook HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n5\r\nhello\r\n0\r\n\r\n").await;
+        assert!(matches!(req, Err(RequestError::AmbiguousLength)));
+    }
+
+    #[tokio::test]
+    async fn repeated_content_lengths_must_agree() {
+        let agreeing = parse(b"POST /hook HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\nhello").await;
+        assert_eq!(agreeing.unwrap().bytes(), b"hello");
+        for lengths in ["Content-Length: 5\r\nContent-Length: 6", "Content-Length: 5, 6"] {
+            let req = parse(format!("POST /hook HTTP/1.1\r\nHost: example.com\r\n{lengths}\r\n\r\nhello!").as_bytes()).await;
+            assert!(matches!(req, Err(RequestError::ContentLength(_))), "{lengths}");
+        }
+    }
+
+    #[tokio::test]
+    async fn unsupported_framing_is_rejected() {
+        let gzip = parse(b"POST /hook HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: gzip, chunked\r\n\r\n").await;
+        assert!(matches!(gzip, Err(RequestError::TransferEncoding(_))));
+        let invalid = parse(b"POST /hook HTTP/1.1\r\nHost: example.com\r\nContent-Length: -1\r\n\r\n").await;
+        assert!(matches!(invalid, Err(RequestError::ContentLength(_))));
+        let undelimited = parse(b"POST /hook HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: gzip\r\n\r\nhello").await;
+        assert!(matches!(undelimited, Err(RequestError::LengthRequired)));
+    }
+
+    #[tokio::test]
+    async fn requests_without_framing_have_an_empty_body() {
+        let req = parse(b"POST /hook HTTP/1.1\r\nHost: example.com\r\n\r\nGET / HTTP/1.1\r\n").await.unwrap();
+        assert_eq!(req.bytes(), b"");
+    }
+
+    #[tokio::test]
+    async fn oversized_trailers_are_rejected() {
+        let limits = Limits { max_header_size: 64, ..Limits::default() };
+        let head = "POST /hook HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n";
+        let within = parse_limited(format!("{head}Expires: never\r\n\r\n").as_bytes(), &limits).await;
+        assert_eq!(within.unwrap().bytes(), b"hello");
+        let oversized = parse_limited(format!("{head}Expires: never\r\nX-Checksum: {}\r\n\r\n", "0123456789abcdef".repeat(4)).as_bytes(), &limits).await;
+        assert!(matches!(oversized, Err(RequestError::HeadersTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn bodies_longer_than_the_limit_are_rejected() {
+        let limits = Limits { max_body_size: 4, ..Limits::default() };
+        let sized = parse_limited(b"POST /hook HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\r\nhello", &limits).await;
+        assert!(matches!(sized, Err(RequestError::BodyTooLarge)));
+        let chunked = parse_limited(b"POST /hook HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n", &limits).await;
+        assert!(matches!(chunked, Err(RequestError::BodyTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn query_parameters_are_decoded() {
+        let req = parse(b"GET /search/?q=a%2Bb+c&tag=v1%2E2&tag=latest&empty&%E2%9C%93=yes HTTP/1.1\r\nHost: example.com\r\n\r\n").await.unwrap();
+        assert_eq!(req.path, "/search");
+        assert_eq!(req.query("q").unwrap(), "a+b c");
+        assert_eq!(req.query["tag"], vec!["v1.2", "latest"]);
+        assert_eq!(req.query("empty").unwrap(), "");
+        assert_eq!(req.query("✓").unwrap(), "yes");
+    }
+
+    #[tokio::test]
+    async fn plus_is_kept_in_the_path() {
+        let req = parse(b"GET /a+b/c%20d HTTP/1.1\r\nHost: example.com\r\n\r\n").await.unwrap();
+        assert_eq!(req.path, "/a+b/c d");
+    }
+
+    #[tokio::test]
+    async fn invalid_escapes_are_rejected() {
+        for target in ["/?q=%zz", "/?q=%4", "/%FF"] {
+            let head = format!("GET {target} HTTP/1.1\r\nHost: example.com\r\n\r\n");
+            assert!(matches!(parse(head.as_bytes()).await, Err(RequestError::Malformed(_))), "{target}");
+        }
+    }
+
+    #[tokio::test]
+    async fn headers_keep_every_value_and_optional_whitespace_is_trimmed() {
+        let req = parse(b"GET / HTTP/1.1\r\nHost: example.com\r\nAccept:text/html\r\naccept: \tapplication/json \r\n\r\n").await.unwrap();
+        assert_eq!(req.headers.get_all("Accept").collect::<Vec<_>>(), vec!["text/html", "application/json"]);
+    }
+
+    #[tokio::test]
+    async fn malformed_heads_are_rejected() {
+        let heads: [&[u8]; 6] = [
+            b"GET /\r\n\r\n",
+            b"G(T / HTTP/1.1\r\nHost: example.com\r\n\r\n",
+            b"GET / HTTP/1.1\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: example.com\r\nNo-Colon\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: example.com\r\nX-Folded: a\r\n b\r\n\r\n",
+            b"GET example.com HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        ];
+        for head in heads {
+            assert!(matches!(parse(head).await, Err(RequestError::Malformed(_))), "{}", String::from_utf8_lossy(head));
+        }
+        assert!(matches!(parse(b"GET / HTTP/2.0\r\n\r\n").await, Err(RequestError::Version(_))));
+    }
+
+    #[tokio::test]
+    async fn inflated_bodies_are_limited() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&[0; 64 * 1024]).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let head = format!("POST /hook HTTP/1.1\r\nHost: example.com\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n", compressed.len());
+        let req = [head.as_bytes(), &compressed].concat();
+        assert_eq!(parse(&req).await.unwrap().bytes().len(), 64 * 1024);
+        let limits = Limits { max_body_size: 16 * 1024, ..Limits::default() };
+        assert!(matches!(parse_limited(&req, &limits).await, Err(RequestError::BodyTooLarge)));
+    }
 }
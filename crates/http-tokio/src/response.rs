@@ -1,12 +1,11 @@
-use crate::{content_type::ContentType, status_code::StatusCode};
+use crate::encoding::{self, ContentEncoding, Encoder, MIN_COMPRESS_SIZE};
+use crate::{connection::Release, content_type::ContentType, file::StaticFile, status_code::StatusCode, Request};
 use std::result::Result as StdResult;
-use std::{collections::HashMap, ffi::OsStr, future::Future, path::Path};
+use std::{collections::HashMap, future::Future, io, path::Path};
 use thiserror::Error;
-use tokio::io::AsyncBufReadExt;
 use tokio::{
-    fs::File,
-    io::{AsyncWriteExt, BufReader},
-    net::TcpStream,
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::tcp::OwnedWriteHalf,
 };
 
 #[derive(Error, Debug)]
@@ -17,6 +16,8 @@ pub enum ResponseError {
     Flush(tokio::io::Error),
     #[error("could not send response: {0}")]
     Sendable(String),
+    #[error("response was already sent")]
+    AlreadySent,
 }
 
 impl ResponseError {
@@ -33,20 +34,59 @@ type Result<T> = StdResult<T, ResponseError>;
 pub struct Response {
     status: StatusCode,
     headers: HashMap<String, String>,
-    stream: TcpStream,
+    stream: Option<OwnedWriteHalf>,
+    release: Option<Release>,
+    chunked: bool,
+    /// coding accepted by the client for compressible bodies
+    encoding: Option<ContentEncoding>,
+    /// answers a HEAD request, the body is not written
+    head: bool,
+    /// whether anything was written to the stream
+    started: bool,
     sent: bool,
 }
 
 // constructor
 impl Response {
-    pub fn new(stream: TcpStream) -> Response {
+    pub(crate) fn new(stream: OwnedWriteHalf, release: Release) -> Response {
         Self {
             status: StatusCode::Ok,
             headers: HashMap::new(),
-            stream,
+            stream: Some(stream),
+            release: Some(release),
+            chunked: true,
+            encoding: None,
+            head: false,
+            started: false,
             sent: false,
         }
     }
+
+    /// Disables chunked transfer encoding, for HTTP/1.0 clients
+    pub(crate) fn disable_chunked(&mut self) {
+        self.chunked = false;
+    }
+
+    /// Sends the headers only, for HEAD requests
+    pub(crate) fn head_request(&mut self) {
+        self.head = true;
+    }
+
+    /// Sets the coding negotiated from the `Accept-Encoding` request header
+    pub(crate) fn accept_encoding(&mut self, encoding: Option<ContentEncoding>) {
+        self.encoding = encoding;
+    }
+}
+
+/// Hands the stream back to the connection, which is reused only after a complete response
+impl Drop for Response {
+    fn drop(&mut self) {
+        let stream = self.stream.take();
+        if let Some(release) = self.release.take() {
+            let reusable = self.sent && !self.closes_connection();
+            let _ = release.send(stream.filter(|_| reusable));
+        }
+    }
 }
 
 // public methods
@@ -57,7 +97,10 @@ impl Response {
 
     /// Sends without body
     pub async fn try_send_empty(&mut self) -> Result<()> {
-        self.headers.insert("Content-Length".to_owned(), 0.to_string());
+        self.ensure_not_sent()?;
+        if !self.bodiless() {
+            self.headers.insert("Content-Length".to_owned(), 0.to_string());
+        }
         self.write(self.fmt_head()).await?;
         self.flush().await?;
         Ok(())
@@ -65,26 +108,87 @@ impl Response {
 
     /// Sends without the body with error fallback to status code 500
     pub async fn send_empty(&mut self) {
-        self.try_send_empty().await.unwrap_or_else(|_| {
-            let _ = self.status(StatusCode::InternalServerError).try_send("Internal server error");
-        });
+        if self.try_send_empty().await.is_err() {
+            self.send_server_error().await;
+        }
     }
 
     /// Sends the body
     pub async fn try_send<T: Sendable>(&mut self, body: T) -> Result<()> {
+        self.ensure_not_sent()?;
         body.prepare(self);
         if !self.headers.contains_key("Content-Type") {
             self.content_type(ContentType::TextPlain);
         }
-        if !self.headers.contains_key("Content-Length") {
-            self.headers.insert("Content-Length".to_owned(), body.content_length());
-        }
+        let bodiless = self.bodiless();
+        let encoder = self.compression(body.content_length()).map(|encoding| {
+            self.headers.insert("Content-Encoding".to_owned(), encoding.as_str().to_owned());
+            let vary = match self.headers.get("Vary") {
+                Some(vary) => format!("{vary}, Accept-Encoding"),
+                None => "Accept-Encoding".to_owned(),
+            };
+            self.headers.insert("Vary".to_owned(), vary);
+            Encoder::new(encoding)
+        });
+        // the compressed length is only known once the body is written
+        let length = body.content_length().filter(|_| encoder.is_none());
+        let chunked = match length {
+            _ if bodiless => {
+                self.headers.remove("Content-Length");
+                false
+            }
+            Some(len) => {
+                if !self.headers.contains_key("Content-Length") {
+                    self.headers.insert("Content-Length".to_owned(), len.to_string());
+                }
+                false
+            }
+            None if self.chunked => {
+                self.headers.remove("Content-Length");
+                self.headers.insert("Transfer-Encoding".to_owned(), "chunked".to_owned());
+                true
+            }
+            // without chunked encoding the end of the body is signaled by closing the connection
+            None => {
+                self.headers.remove("Content-Length");
+                self.headers.insert("Connection".to_owned(), "close".to_owned());
+                false
+            }
+        };
         self.write(self.fmt_head()).await?;
-        body.write(&mut self.stream).await?;
+        // a HEAD response carries the headers of the GET response without its body
+        if !bodiless && !self.head {
+            let mut writer = BodyWriter {
+                stream: self.stream(),
+                chunked,
+                encoder,
+            };
+            body.write(&mut writer).await?;
+            writer.finish().await?;
+        }
         self.flush().await?;
         Ok(())
     }
 
+    /// Sends a file, answers 404 if it does not exist
+    pub async fn try_send_file<P: AsRef<Path>>(&mut self, req: &Request, path: P) -> Result<()> {
+        let path = path.as_ref();
+        match StaticFile::open(path, req).await {
+            Ok(file) => self.try_send(file).await,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                self.status(StatusCode::NotFound).try_send("404 NotFound").await
+            }
+            Err(err) => Err(ResponseError::Sendable(format!("could not open file {}: {}", path.display(), err))),
+        }
+    }
+
+    /// Sends a file with error fallback to status code 500
+    pub async fn send_file<P: AsRef<Path>>(&mut self, req: &Request, path: P) {
+        if self.try_send_file(req, path).await.is_err() {
+            self.send_server_error().await;
+        }
+    }
+
     /// Sends the body and in case of failure computes the closure
     pub async fn send_or<T: Sendable, F: FnOnce(&mut Self)>(&mut self, body: T, op: F) {
         self.try_send(body).await.unwrap_or_else(|_| op(self));
@@ -92,10 +196,16 @@ impl Response {
 
     /// Sends the body with error fallback to status code 500
     pub async fn send<T: Sendable>(&mut self, body: T) {
-        self.send_or(body, |res| {
-            let _ = res.status(StatusCode::InternalServerError).try_send("Internal server error");
-        })
-        .await;
+        if self.try_send(body).await.is_err() {
+            self.send_server_error().await;
+        }
+    }
+
+    /// Sends a 500 response if nothing was written yet, otherwise the connection is closed on drop
+    pub async fn send_server_error(&mut self) {
+        if !self.started {
+            let _ = self.status(StatusCode::InternalServerError).try_send("Internal server error").await;
+        }
     }
 
     /// Sets the content type
@@ -116,21 +226,71 @@ impl Response {
         self
     }
 
+    /// Returns the status code
+    pub fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Returns a header set on the response, names are case-insensitive
+    pub fn header(&self, k: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(k))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns whether writing the response has started, it can't be replaced anymore
+    pub fn started(&self) -> bool {
+        self.started
+    }
+
     /// Returns whether the stream has been flushed
     pub fn sent(&self) -> bool {
         self.sent
     }
+
+    /// Returns whether the connection is closed after this response
+    pub fn closes_connection(&self) -> bool {
+        self.headers.get("Connection").is_some_and(|v| v.eq_ignore_ascii_case("close"))
+    }
 }
 
 // private methods
 impl Response {
+    fn stream(&mut self) -> &mut OwnedWriteHalf {
+        self.stream.as_mut().expect("response stream is only taken on drop")
+    }
+
+    /// The coding to compress the body with, if the client accepts one and compression is worth it
+    fn compression(&self, length: Option<u64>) -> Option<ContentEncoding> {
+        let encoding = self.encoding?;
+        let compressible = self.headers.get("Content-Type").is_some_and(|c| encoding::is_compressible(c));
+        let large_enough = length.is_none_or(|len| len >= MIN_COMPRESS_SIZE);
+        // ranges refer to the uncompressed representation
+        let encoded = self.headers.contains_key("Content-Encoding") || self.headers.contains_key("Content-Range");
+        (compressible && large_enough && !self.bodiless() && !encoded).then_some(encoding)
+    }
+
+    /// 204 and 304 responses have neither a body nor framing headers
+    fn bodiless(&self) -> bool {
+        matches!(self.status, StatusCode::NoContent | StatusCode::NotModified)
+    }
+
+    fn ensure_not_sent(&self) -> Result<()> {
+        match self.sent {
+            true => Err(ResponseError::AlreadySent),
+            false => Ok(()),
+        }
+    }
+
     async fn write(&mut self, res: String) -> Result<()> {
-        self.stream.write(res.as_bytes()).await?;
+        self.started = true;
+        self.stream().write_all(res.as_bytes()).await?;
         Ok(())
     }
 
     async fn flush(&mut self) -> Result<()> {
-        self.stream.flush().await.map_err(|e| ResponseError::Flush(e))?;
+        self.stream().flush().await.map_err(ResponseError::Flush)?;
         self.sent = true;
         Ok(())
     }
@@ -147,72 +307,107 @@ impl Response {
     }
 }
 
+/// Writes the body after the headers, compressing it when negotiated
+/// and framing it in chunks when the length is unknown
+pub struct BodyWriter<'a> {
+    stream: &'a mut OwnedWriteHalf,
+    chunked: bool,
+    encoder: Option<Encoder>,
+}
+
+impl BodyWriter<'_> {
+    pub async fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        match self.encoder.as_mut() {
+            Some(encoder) => {
+                let compressed = encoder.write(buf)?;
+                self.write_frame(&compressed).await
+            }
+            None => self.write_frame(buf).await,
+        }
+    }
+
+    /// Sends what was written so far even if the body is compressed, for streamed bodies
+    pub async fn flush(&mut self) -> Result<()> {
+        if let Some(encoder) = self.encoder.as_mut() {
+            let compressed = encoder.flush()?;
+            self.write_frame(&compressed).await?;
+        }
+        self.stream.flush().await.map_err(ResponseError::Flush)
+    }
+
+    async fn write_frame(&mut self, buf: &[u8]) -> Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        if self.chunked {
+            self.stream.write_all(format!("{:X}\r\n", buf.len()).as_bytes()).await?;
+            self.stream.write_all(buf).await?;
+            self.stream.write_all(b"\r\n").await?;
+        } else {
+            self.stream.write_all(buf).await?;
+        }
+        Ok(())
+    }
+
+    async fn finish(mut self) -> Result<()> {
+        if let Some(encoder) = self.encoder.take() {
+            let compressed = encoder.finish()?;
+            self.write_frame(&compressed).await?;
+        }
+        if self.chunked {
+            self.stream.write_all(b"0\r\n\r\n").await?;
+        }
+        Ok(())
+    }
+}
+
 pub trait Sendable {
     /// method executed before writing the headers
     fn prepare(&self, res: &mut Response);
     /// method executed after writing the headers
-    fn write(&self, stream: &mut TcpStream) -> impl Future<Output = Result<()>>;
-    /// used to determine the content length header
-    fn content_length(&self) -> String;
+    fn write(self, body: &mut BodyWriter<'_>) -> impl Future<Output = Result<()>>;
+    /// used to determine the content length header, `None` sends the body with chunked encoding
+    fn content_length(&self) -> Option<u64>;
 }
 
 impl Sendable for String {
     fn prepare(&self, _: &mut Response) {}
-    async fn write(&self, stream: &mut TcpStream) -> Result<()> {
-        let mut bytes = self.as_bytes();
-        while !bytes.is_empty() {
-            let written = stream.write(bytes).await.map_err(|err| ResponseError::Sendable(format!("{err}")))?;
-            bytes = &bytes[written..];
-        }
-        Ok(())
+    async fn write(self, body: &mut BodyWriter<'_>) -> Result<()> {
+        body.write_all(self.as_bytes()).await
     }
-    fn content_length(&self) -> String {
-        self.len().to_string()
+    fn content_length(&self) -> Option<u64> {
+        Some(self.len() as u64)
     }
 }
 
 impl Sendable for &str {
     fn prepare(&self, _: &mut Response) {}
-    async fn write(&self, stream: &mut TcpStream) -> Result<()> {
-        let mut bytes = self.as_bytes();
-        while !bytes.is_empty() {
-            let written = stream.write(bytes).await.map_err(|err| ResponseError::sendable(err))?;
-            bytes = &bytes[written..];
-        }
-        Ok(())
+    async fn write(self, body: &mut BodyWriter<'_>) -> Result<()> {
+        body.write_all(self.as_bytes()).await
     }
-    fn content_length(&self) -> String {
-        self.len().to_string()
+    fn content_length(&self) -> Option<u64> {
+        Some(self.len() as u64)
     }
 }
 
-impl Sendable for &Path {
-    fn prepare(&self, res: &mut Response) {
-        let ext = self.extension().unwrap_or(OsStr::new("")).to_str().unwrap_or("");
-        res.content_type(ContentType::from_ext(ext));
-    }
+/// Streams a reader of unknown length with chunked transfer encoding
+pub struct Streaming<R>(pub R);
 
-    async fn write(&self, stream: &mut TcpStream) -> Result<()> {
-        let file = File::open(self)
-            .await
-            .map_err(|err| ResponseError::Sendable(format!("could not open file {}: {}", self.display(), err)))?;
-        let mut reader = BufReader::new(file);
-        let mut buf = Vec::<u8>::new();
-        while let Ok(read) = reader.read_until(b"\n"[0], &mut buf).await {
+impl<R: AsyncRead + Unpin> Sendable for Streaming<R> {
+    fn prepare(&self, _: &mut Response) {}
+    async fn write(mut self, body: &mut BodyWriter<'_>) -> Result<()> {
+        let mut buf = vec![0_u8; 8 * 1024];
+        loop {
+            let read = self.0.read(&mut buf).await.map_err(ResponseError::sendable)?;
             if read == 0 {
                 break;
             }
-            stream.write_all(&buf).await.map_err(|err| ResponseError::sendable(err))?;
-            // buf = Vec::<u8>::new();
-            buf.clear();
+            body.write_all(&buf[..read]).await?;
+            body.flush().await?;
         }
         Ok(())
     }
-
-    fn content_length(&self) -> String {
-        match std::fs::metadata(self) {
-            Ok(metadata) => metadata.len().to_string(),
-            Err(_) => "0".into(),
-        }
+    fn content_length(&self) -> Option<u64> {
+        None
     }
 }
@@ -0,0 +1,99 @@
+use crate::response::{BodyWriter, Response, ResponseError, Sendable};
+use std::result::Result as StdResult;
+use std::time::Duration;
+use tokio::sync::mpsc::Receiver;
+use tokio::time::timeout;
+
+/// An event of a `text/event-stream` response
+#[derive(Debug, Clone, Default)]
+pub struct ServerEvent {
+    pub event: Option<String>,
+    pub id: Option<String>,
+    pub data: String,
+}
+
+impl ServerEvent {
+    pub fn new(data: impl Into<String>) -> Self {
+        Self {
+            data: data.into(),
+            ..Self::default()
+        }
+    }
+
+    /// The event name, the browser dispatches it to the listeners of that name instead of `message`
+    pub fn event(mut self, event: impl Into<String>) -> Self {
+        self.event = Some(event.into());
+        self
+    }
+
+    /// Sent back by the browser in `Last-Event-ID` when it reconnects
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    fn encode(&self) -> String {
+        let mut encoded = String::new();
+        if let Some(event) = &self.event {
+            encoded.push_str(&format!("event: {event}\n"));
+        }
+        if let Some(id) = &self.id {
+            encoded.push_str(&format!("id: {id}\n"));
+        }
+        // a line break would end the field, multi-line data is sent as several data fields
+        for line in self.data.split('\n') {
+            encoded.push_str(&format!("data: {line}\n"));
+        }
+        encoded.push('\n');
+        encoded
+    }
+}
+
+/// Streams the events received on the channel as server-sent events,
+/// the response ends when all the senders are dropped or the client goes away
+pub struct EventStream {
+    events: Receiver<ServerEvent>,
+    keep_alive: Option<Duration>,
+}
+
+impl EventStream {
+    pub fn new(events: Receiver<ServerEvent>) -> Self {
+        Self { events, keep_alive: None }
+    }
+
+    /// Sends a comment when no event was sent for `interval`, so that proxies don't close the idle stream
+    pub fn keep_alive(mut self, interval: Duration) -> Self {
+        self.keep_alive = Some(interval);
+        self
+    }
+}
+
+impl Sendable for EventStream {
+    fn prepare(&self, res: &mut Response) {
+        res.raw_content_type("text/event-stream");
+        res.set_header("Cache-Control", "no-cache");
+    }
+
+    async fn write(mut self, body: &mut BodyWriter<'_>) -> StdResult<(), ResponseError> {
+        loop {
+            let event = match self.keep_alive {
+                Some(interval) => match timeout(interval, self.events.recv()).await {
+                    Ok(event) => event,
+                    Err(_) => {
+                        body.write_all(b": keep-alive\n\n").await?;
+                        body.flush().await?;
+                        continue;
+                    }
+                },
+                None => self.events.recv().await,
+            };
+            let Some(event) = event else { return Ok(()) };
+            body.write_all(event.encode().as_bytes()).await?;
+            body.flush().await?;
+        }
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        None
+    }
+}
@@ -1,12 +1,15 @@
-use crate::{request::Request, Response};
+use crate::{
+    connection::{Connection, ConnectionConfig},
+    request::Request,
+    Response,
+};
 use std::time::SystemTime;
 use tokio::net::TcpListener;
 
-pub async fn accept_connection(server: &TcpListener) -> Result<(Request, Response), String> {
-    let (mut stream, addr) = server.accept().await.map_err(|err| format!("couldn't get client: {err}"))?;
-    let req = Request::parse((&mut stream, addr)).await.map_err(|err| format!("{err}"))?;
-    let res = Response::new(stream);
-    Ok((req, res))
+/// Accepts a client, requests are then read with [`Connection::next`]
+pub async fn accept_connection(server: &TcpListener, config: &ConnectionConfig) -> Result<Connection, String> {
+    let (stream, addr) = server.accept().await.map_err(|err| format!("couldn't get client: {err}"))?;
+    Ok(Connection::new(stream, addr, config.clone()))
 }
 
 /// [MDN: Access-Control-Allow-Origin](https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Access-Control-Allow-Origin)
@@ -20,12 +23,17 @@ pub fn cors_allow_origin(req: &Request, res: &mut Response, allowed_origins: &[&
 }
 
 pub fn print_request(req: &Request) {
+    let method = format!("[\x1b[96;1m{}\x1b[0m]", req.method);
+    println!("\x1b[2m{}\x1b[0m {: <19} {}", fmt_now(), method, req.path);
+}
+
+pub(crate) fn fmt_now() -> String {
     let current_time = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .expect("Time went backwards")
         .as_secs();
 
-    let curr_date = format!(
+    format!(
         "{}-{:02}-{:02} {:02}:{:02}:{:02}",
         1970 + current_time / 31536000,
         (current_time % 31536000) / 2592000,
@@ -33,8 +41,5 @@ pub fn print_request(req: &Request) {
         (current_time % 86400) / 3600,
         (current_time % 3600) / 60,
         current_time % 60
-    );
-
-    let method = format!("[\x1b[96;1m{}\x1b[0m]", req.method);
-    println!("\x1b[2m{}\x1b[0m {: <19} {}", curr_date, method, req.path);
+    )
 }
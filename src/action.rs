@@ -0,0 +1,232 @@
+use crate::{
+    compose::{CmdOutput, ComposeCmd},
+    config::Config,
+    deploy::DeployTarget,
+    prelude::*,
+};
+use anyhow::{bail, Context};
+use http_tokio::Client;
+use serde::Deserialize;
+use std::{collections::HashMap, time::Duration};
+use tokio::{
+    process::Command,
+    time::{sleep, Instant},
+};
+
+/// The names usable as `{{name}}` in the templated fields of the actions
+pub const VARIABLES: [&str; 10] = ["listener", "compose_path", "service", "services", "action", "host", "repository", "tag", "digest", "image"];
+
+/// Pause between the health checks of `wait_healthy`
+const HEALTH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A step of the action chain a listener runs for the services matched by a push
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Action {
+    /// `docker compose pull` of the matched services
+    ComposePull,
+    /// `docker compose up -d` of the matched services
+    ComposeUp,
+    /// waits until the containers of the matched services are running and healthy, if they have a healthcheck
+    WaitHealthy {
+        /// seconds
+        #[serde(default = "Action::default_health_timeout")]
+        timeout: u64,
+    },
+    /// removes the dangling images left by the pull
+    CleanDangling,
+    /// runs a program in the directory of the compose file
+    Exec {
+        command: Vec<String>,
+        #[serde(default = "HashMap::default")]
+        env: HashMap<String, String>,
+    },
+    /// `docker compose run --rm` of a one-off service, like a migration
+    ComposeRun {
+        service: String,
+        #[serde(default = "Vec::default")]
+        command: Vec<String>,
+    },
+    HttpRequest {
+        url: String,
+        #[serde(default = "Action::default_method")]
+        method: String,
+        #[serde(default = "HashMap::default")]
+        headers: HashMap<String, String>,
+        body: Option<String>,
+    },
+}
+
+impl Action {
+    fn default_method() -> String { String::from("POST") }
+    fn default_health_timeout() -> u64 { 60 }
+
+    /// The chain of the listeners without `actions`
+    pub fn default_chain() -> Vec<Action> {
+        let mut chain = vec![Action::ComposePull, Action::ComposeUp];
+        if Config::global().remove_dangling {
+            chain.push(Action::CleanDangling);
+        }
+        chain
+    }
+
+    /// The templates of the action, checked against [`VARIABLES`] when the configuration is loaded
+    pub fn templates(&self) -> Vec<&String> {
+        match self {
+            Action::ComposePull | Action::ComposeUp | Action::WaitHealthy { .. } | Action::CleanDangling => vec![],
+            Action::Exec { command, env } => command.iter().chain(env.values()).collect(),
+            Action::ComposeRun { service, command } => std::iter::once(service).chain(command.iter()).collect(),
+            Action::HttpRequest { url, method: _, headers, body } => std::iter::once(url).chain(headers.values()).chain(body.iter()).collect(),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::ComposePull => "compose_pull",
+            Action::ComposeUp => "compose_up",
+            Action::WaitHealthy { .. } => "wait_healthy",
+            Action::CleanDangling => "clean_dangling",
+            Action::Exec { .. } => "exec",
+            Action::ComposeRun { .. } => "compose_run",
+            Action::HttpRequest { .. } => "http_request",
+        }
+    }
+
+    /// Runs the action, the exit code and outputs of its command are kept in `output`
+    pub async fn run(&self, target: &DeployTarget, vars: &Variables, output: &mut CmdOutput) -> Result<()> {
+        let compose = ComposeCmd::new(&target.compose_path);
+        match self {
+            Action::ComposePull => {
+                *output = compose.pull_services(&target.services).await?;
+                output.check("docker compose pull")
+            }
+            Action::ComposeUp => {
+                *output = compose.restart_services(&target.services).await?;
+                output.check("docker compose up")
+            }
+            Action::WaitHealthy { timeout } => {
+                let deadline = Instant::now() + Duration::from_secs(*timeout);
+                for service in target.services.iter() {
+                    loop {
+                        let health = compose.health(service).await?;
+                        output.stdout = f!("{service}: {health}");
+                        // the health is missing for the containers without healthcheck
+                        match health.split_once(' ').unwrap_or((&health, "")) {
+                            ("running", "" | "healthy") => break,
+                            ("running", "unhealthy") => bail!("service {service} is unhealthy"),
+                            ("", _) => bail!("service {service} has no container"),
+                            ("running" | "restarting" | "created", _) if Instant::now() < deadline => sleep(HEALTH_INTERVAL).await,
+                            ("running" | "restarting" | "created", _) => bail!("service {service} is still {health} after {timeout}s"),
+                            _ => bail!("service {service} is {health}"),
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Action::CleanDangling => {
+                // docker names the images as the compose file does, not with the canonical registry host
+                let listener = &Config::global().listeners[&target.listener];
+                for repository in target.services.iter().filter_map(|service| listener.compose_repository(service)) {
+                    ComposeCmd::clean_dangling(repository).await?;
+                }
+                Ok(())
+            }
+            Action::Exec { command, env } => {
+                let Some((program, args)) = command.split_first() else { bail!("exec without command") };
+                let out = Command::new(vars.render(program))
+                    .args(args.iter().map(|arg| vars.render(arg)))
+                    .envs(env.iter().map(|(name, value)| (name, vars.render(value))))
+                    .current_dir(compose.dir())
+                    .output()
+                    .await
+                    .context(f!("failed to run `{program}`"))?;
+                *output = out.into();
+                output.check(program)
+            }
+            Action::ComposeRun { service, command } => {
+                let service = vars.render(service);
+                let command = command.iter().map(|arg| vars.render(arg)).collect::<Vec<_>>();
+                *output = compose.run_service(&service, &command).await?;
+                output.check(&f!("docker compose run {service}"))
+            }
+            Action::HttpRequest { url, method, headers, body } => {
+                let client = Client::new();
+                let mut req = client.request(method, &vars.render(url));
+                for (name, value) in headers.iter() {
+                    req = req.header(name, &vars.render(value));
+                }
+                if let Some(body) = body {
+                    req = req.body(vars.render(body));
+                }
+                let res = req.send().await.context(f!("failed to call {url}"))?;
+                output.stdout = f!("{} {}", res.status, res.reason);
+                if !res.is_success() {
+                    bail!("{url} answered {} {}", res.status, res.reason);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The values of the [`VARIABLES`] for a target
+#[derive(Debug)]
+pub struct Variables(HashMap<&'static str, String>);
+
+impl Variables {
+    pub fn of(target: &DeployTarget) -> Self {
+        let event = &target.event;
+        let host = match Config::global().listeners.get(&target.listener) {
+            Some(listener) => listener.canonical_host(&event.host).to_owned(),
+            None => Config::global().canonical_host(&event.host).to_owned(),
+        };
+        let tag = event.tag.clone().unwrap_or_default();
+        let image = match (&event.tag, &event.digest) {
+            (Some(tag), _) => f!("{host}/{}:{tag}", event.repository),
+            (None, Some(digest)) => f!("{host}/{}@{digest}", event.repository),
+            (None, None) => f!("{host}/{}", event.repository),
+        };
+        let values = [
+            target.listener.clone(),
+            target.compose_path.clone(),
+            target.services.first().cloned().unwrap_or_default(),
+            target.services.join(" "),
+            event.action.to_string(),
+            host,
+            event.repository.clone(),
+            tag,
+            event.digest.clone().unwrap_or_default(),
+            image,
+        ];
+        Self(VARIABLES.into_iter().zip(values).collect())
+    }
+
+    /// Adds a variable, like the ones of the notifications
+    pub fn with(mut self, name: &'static str, value: String) -> Self {
+        self.0.insert(name, value);
+        self
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        self.0.iter().map(|(name, value)| (*name, value.as_str()))
+    }
+
+    /// Replaces the `{{name}}` placeholders
+    pub fn render(&self, template: &str) -> String {
+        let mut rendered = template.to_owned();
+        for (name, value) in self.0.iter() {
+            rendered = rendered.replace(&f!("{{{{{name}}}}}"), value);
+        }
+        rendered
+    }
+}
+
+/// The `{{name}}` placeholders of a template that are neither in [`VARIABLES`] nor in `extra`
+pub fn unknown_variables<'a>(template: &'a str, extra: &[&str]) -> Vec<&'a str> {
+    template
+        .split("{{")
+        .skip(1)
+        .filter_map(|rest| rest.split_once("}}").map(|(name, _)| name))
+        .filter(|name| !VARIABLES.contains(name) && !extra.contains(name))
+        .collect()
+}
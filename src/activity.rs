@@ -0,0 +1,149 @@
+use crate::{
+    config::Reaction,
+    deploy::{JobKind, JobState},
+    hook::HookStage,
+    webhook::{EventAction, RegistryEvent},
+};
+use http_tokio::ServerEvent;
+use serde::Serialize;
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc, OnceCell,
+};
+use tokio::task;
+
+/// Activities kept for the slow subscribers before they miss some
+const CAPACITY: usize = 256;
+
+static FEED: OnceCell<ActivityFeed> = OnceCell::const_new();
+
+/// What the daemon is doing, broadcast to the subscribers of `GET /events`
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Activity {
+    RegistryEvent {
+        action: EventAction,
+        host: String,
+        repository: String,
+        tag: Option<String>,
+        digest: Option<String>,
+    },
+    ListenerMatch {
+        listener: String,
+        service: String,
+        action: EventAction,
+        reaction: Reaction,
+    },
+    DeploymentQueued {
+        deployment: u64,
+        kind: JobKind,
+        services: Vec<String>,
+    },
+    StepStarted {
+        deployment: u64,
+        listener: String,
+        action: String,
+    },
+    StepFinished {
+        deployment: u64,
+        listener: String,
+        action: String,
+        state: JobState,
+        exit_code: Option<i32>,
+        error: Option<String>,
+    },
+    HookFinished {
+        deployment: u64,
+        listener: String,
+        stage: HookStage,
+        exit_code: Option<i32>,
+    },
+    DeploymentFinished {
+        deployment: u64,
+        state: JobState,
+        error: Option<String>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl Activity {
+    pub fn registry_event(event: &RegistryEvent) -> Self {
+        Activity::RegistryEvent {
+            action: event.action,
+            host: event.host.clone(),
+            repository: event.repository.clone(),
+            tag: event.tag.clone(),
+            digest: event.digest.clone(),
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Activity::Error { message: message.into() }
+    }
+
+    /// Sends the activity to the current subscribers, it is lost if there are none
+    pub fn publish(self) {
+        let _ = ActivityFeed::global().sender.send(self);
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Activity::RegistryEvent { .. } => "registry_event",
+            Activity::ListenerMatch { .. } => "listener_match",
+            Activity::DeploymentQueued { .. } => "deployment_queued",
+            Activity::StepStarted { .. } => "step_started",
+            Activity::StepFinished { .. } => "step_finished",
+            Activity::HookFinished { .. } => "hook_finished",
+            Activity::DeploymentFinished { .. } => "deployment_finished",
+            Activity::Error { .. } => "error",
+        }
+    }
+}
+
+/// The broadcast channel of the activities.
+///
+/// needs to be started once with ActivityFeed::start()
+pub struct ActivityFeed {
+    sender: broadcast::Sender<Activity>,
+}
+
+impl ActivityFeed {
+    pub fn global() -> &'static Self {
+        FEED.get().expect("activity feed is not started")
+    }
+
+    /// panics if the feed was already started
+    pub fn start() {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        if FEED.set(Self { sender }).is_err() {
+            panic!("activity feed is already started");
+        }
+    }
+
+    /// The activities from now on as server-sent events named after their type,
+    /// a subscriber too slow to keep up gets a `lagged` event with the number of missed activities
+    pub fn subscribe(&self) -> mpsc::Receiver<ServerEvent> {
+        let mut activities = self.sender.subscribe();
+        let (events, receiver) = mpsc::channel(16);
+        task::spawn(async move {
+            loop {
+                // a client gone while no activity happens would keep the task alive
+                let received = tokio::select! {
+                    received = activities.recv() => received,
+                    _ = events.closed() => return,
+                };
+                let event = match received {
+                    Ok(activity) => ServerEvent::new(serde_json::to_string(&activity).unwrap_or_default()).event(activity.name()),
+                    Err(RecvError::Lagged(missed)) => ServerEvent::new(missed.to_string()).event("lagged"),
+                    Err(RecvError::Closed) => return,
+                };
+                if events.send(event).await.is_err() {
+                    return;
+                }
+            }
+        });
+        receiver
+    }
+}
@@ -1,9 +1,62 @@
 use crate::prelude::*;
 use anyhow::Context;
+use std::path::Path;
 use std::process::Command as SyncCommand;
-use std::process::Stdio;
+use serde::Serialize;
+use std::process::Output;
 use tokio::process::Command;
 
+/// Bytes of output kept in the deployment records
+const OUTPUT_TAIL: usize = 4096;
+
+/// The exit code and the end of the outputs of a command
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CmdOutput {
+    /// missing if the command was killed by a signal or not run
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CmdOutput {
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+
+    /// Fails with the end of stderr if the command did not succeed
+    pub fn check(&self, command: &str) -> Result<()> {
+        if !self.success() {
+            let code = self.exit_code.map_or("no exit code".into(), |code| f!("exit code {code}"));
+            anyhow::bail!("`{command}` failed with {code}: {}", self.stderr.trim());
+        }
+        Ok(())
+    }
+}
+
+impl From<Output> for CmdOutput {
+    fn from(out: Output) -> Self {
+        Self {
+            exit_code: out.status.code(),
+            stdout: tail(&out.stdout),
+            stderr: tail(&out.stderr),
+        }
+    }
+}
+
+/// The last bytes of an output, cut at a line when possible
+pub fn tail(output: &[u8]) -> String {
+    let output = String::from_utf8_lossy(output);
+    if output.len() <= OUTPUT_TAIL {
+        return output.into_owned();
+    }
+    let mut start = output.len() - OUTPUT_TAIL;
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    let tail = &output[start..];
+    tail.split_once('\n').map_or(tail, |(_, rest)| rest).to_owned()
+}
+
 pub struct ComposeCmd {
     compose_path: String,
 }
@@ -26,22 +79,85 @@ impl ComposeCmd {
         Ok(config_str.into_owned())
     }
 
-    pub async fn pull_services(&self, services: &Vec<String>) -> Result<()> {
-        self.compose_cmd()
-            .args(&["pull", &services.join(" ")])
+    pub async fn pull_services(&self, services: &[String]) -> Result<CmdOutput> {
+        let out = self.compose_cmd()
+            .arg("pull")
+            .args(services)
             .output()
             .await
             .context("failed to pull new docker images")?;
-        Ok(())
+        Ok(out.into())
     }
 
-    pub async fn restart_services(&self, services: &Vec<String>) -> Result<()> {
-        self.compose_cmd()
-            .args(&["up", &services.join(" "), "-d"])
+    pub async fn restart_services(&self, services: &[String]) -> Result<CmdOutput> {
+        let out = self.compose_cmd()
+            .args(["up", "-d"])
+            .args(services)
             .output()
             .await
-            .context("failed to pull new docker images")?;
-        Ok(())
+            .context("failed to restart docker services")?;
+        Ok(out.into())
+    }
+
+    pub async fn stop_services(&self, services: &[String]) -> Result<CmdOutput> {
+        let out = self.compose_cmd()
+            .arg("stop")
+            .args(services)
+            .output()
+            .await
+            .context("failed to stop docker services")?;
+        Ok(out.into())
+    }
+
+    /// `docker compose run --rm` of a one-off service
+    pub async fn run_service(&self, service: &str, command: &[String]) -> Result<CmdOutput> {
+        let out = self.compose_cmd()
+            .args(["run", "--rm", service])
+            .args(command)
+            .output()
+            .await
+            .context(f!("failed to run service {service}"))?;
+        Ok(out.into())
+    }
+
+    /// `running healthy`, `running` without healthcheck, or the state of the stopped container,
+    /// empty if the service has no container
+    pub async fn health(&self, service: &str) -> Result<String> {
+        // `-a` also lists the containers that exited, so that a crash is reported as such
+        let out = self.compose_cmd()
+            .args(["ps", "-a", "-q", service])
+            .output()
+            .await
+            .context("failed to list the service containers")?;
+        let containers = String::from_utf8_lossy(&out.stdout);
+        let Some(container) = containers.split_whitespace().next() else {
+            return Ok(String::new());
+        };
+        let out = Command::new("docker")
+            .args(["inspect", "--format", "{{.State.Status}} {{if .State.Health}}{{.State.Health.Status}}{{end}}", container])
+            .output()
+            .await
+            .context("failed to inspect the service container")?;
+        Ok(String::from_utf8_lossy(&out.stdout).trim().to_owned())
+    }
+
+    /// Pulls an image by reference, like `registry:5000/app@sha256:...`
+    pub async fn pull_image(reference: &str) -> Result<CmdOutput> {
+        let out = Command::new("docker")
+            .args(["pull", reference])
+            .output()
+            .await
+            .context(f!("failed to pull {reference}"))?;
+        Ok(out.into())
+    }
+
+    pub async fn tag_image(source: &str, target: &str) -> Result<CmdOutput> {
+        let out = Command::new("docker")
+            .args(["tag", source, target])
+            .output()
+            .await
+            .context(f!("failed to tag {source} as {target}"))?;
+        Ok(out.into())
     }
 
     pub async fn clean_dangling(image_name: &str) -> Result<()> {
@@ -68,9 +184,49 @@ impl ComposeCmd {
         Ok(())
     }
 
+    /// The repo digests of the image of the service container, stopped or not, empty if the service has no container
+    pub async fn running_digests(&self, service: &str) -> Result<Vec<String>> {
+        let out = Command::new("docker")
+            .args(self.compose_args())
+            .args(["ps", "-a", "-q", service])
+            .output()
+            .await
+            .context("failed to list the service containers")?;
+        let containers = String::from_utf8_lossy(&out.stdout);
+        let Some(container) = containers.split_whitespace().next() else {
+            return Ok(vec![]);
+        };
+        let out = Command::new("docker")
+            .args(["inspect", "--format", "{{.Image}}", container])
+            .output()
+            .await
+            .context("failed to inspect the service container")?;
+        if !out.status.success() {
+            anyhow::bail!("failed to inspect container {container}: {}", String::from_utf8_lossy(&out.stderr).trim());
+        }
+        let image_id = String::from_utf8_lossy(&out.stdout).trim().to_owned();
+        let out = Command::new("docker")
+            .args(["image", "inspect", "--format", "{{range .RepoDigests}}{{println .}}{{end}}", &image_id])
+            .output()
+            .await
+            .context("failed to inspect the service image")?;
+        // `registry:5000/app@sha256:...`, one per repository the image was pulled from
+        let digests = String::from_utf8_lossy(&out.stdout)
+            .lines()
+            .filter_map(|line| line.split_once('@'))
+            .map(|(_, digest)| digest.to_owned())
+            .collect();
+        Ok(digests)
+    }
+
+    /// The directory of the compose file, where the actions and hooks run
+    pub fn dir(&self) -> &Path {
+        Path::new(&self.compose_path).parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."))
+    }
+
     pub fn compose_cmd(&self) -> Command {
         let mut cmd = Command::new("docker");
-        cmd.stdout(Stdio::null()).args(self.compose_args());
+        cmd.args(self.compose_args());
         cmd
     }
 
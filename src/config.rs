@@ -1,10 +1,10 @@
-use crate::{compose::ComposeCmd, prelude::*};
+use crate::{action::{self, Action}, compose::ComposeCmd, image::ImageRef, notify::{self, Notifier}, prelude::*, webhook::EventAction};
 use clap::Parser;
 use docker_compose_types::Compose;
 use serde_yaml::Deserializer as YamlDeserializer;
 use core::panic;
-use serde::{Deserialize, Deserializer};
-use std::{collections::HashMap, path::Path};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{collections::HashMap, path::Path, time::Duration};
 use tokio::{fs::File, io::AsyncReadExt, sync::{OnceCell, SetError}};
 
 // TODO: for config path better use an env variable and set it in the .bashrc via installer 
@@ -31,6 +31,12 @@ pub struct Config {
     pub listeners: HashMap<String, Listener>,
     #[serde(default="bool::default")]
     pub remove_dangling: bool,
+    /// access to the registry API, by registry host
+    #[serde(default="HashMap::default")]
+    pub registries: HashMap<String, RegistryConfig>,
+    /// sinks of the deployment results, by name
+    #[serde(default="HashMap::default")]
+    pub notifications: HashMap<String, Notifier>,
     #[serde(skip_deserializing,default="bool::default")]
     pub test_mode: bool
 }
@@ -57,12 +63,100 @@ pub struct Server {
     pub host: String,
     #[serde(default="Server::default_port")]
     pub port: u16,
-    pub auth_token: Option<String>
+    pub auth_token: Option<String>,
+    /// seconds to wait for the running deployment when shutting down
+    #[serde(default="Server::default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    /// cross-origin access for browser clients, disabled if missing
+    pub cors: Option<CorsConfig>,
 }
 
+#[derive(Debug,Deserialize)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+    #[serde(default="CorsConfig::default_methods")]
+    pub allowed_methods: Vec<String>,
+    #[serde(default="CorsConfig::default_headers")]
+    pub allowed_headers: Vec<String>,
+    #[serde(default="Vec::default")]
+    pub exposed_headers: Vec<String>,
+    #[serde(default="bool::default")]
+    pub allow_credentials: bool,
+    /// seconds the browser may cache the preflight response
+    pub max_age: Option<u64>,
+}
+
+#[derive(Debug,Deserialize)]
+pub struct RegistryConfig {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// plain http instead of https
+    #[serde(default="bool::default")]
+    pub insecure: bool,
+    /// other hostnames of the same registry, like a public name in front of the internal address
+    #[serde(default="Vec::default")]
+    pub aliases: Vec<String>,
+}
+
+#[cfg(test)]
+impl Config {
+    /// the global configuration of the unit tests, `registry.example.com` is an alias of `10.0.0.5:5000`
+    pub fn init_test() {
+        let config = "listeners: {}\nregistries:\n  10.0.0.5:5000:\n    aliases: [registry.example.com]\n";
+        let _ = CONFIG.set(serde_yaml::from_str(config).unwrap());
+    }
+}
 
 impl Server {
     pub fn address(&self) -> String { f!("{}:{}", self.host, self.port) }
+    pub fn shutdown_timeout(&self) -> Duration { Duration::from_secs(self.shutdown_timeout) }
+}
+
+impl Config {
+    /// The configured registry that `host` is an alias of, or `host` itself
+    pub fn canonical_host<'a>(&'a self, host: &'a str) -> &'a str {
+        canonical_host(&self.registries, host)
+    }
+}
+
+impl Listener {
+    pub fn poll_interval(&self) -> Option<Duration> { self.poll_interval.map(Duration::from_secs) }
+    /// pull, up and the cleaning of the dangling images unless configured in `actions`
+    pub fn actions(&self) -> Vec<Action> {
+        self.actions.clone().unwrap_or_else(Action::default_chain)
+    }
+    /// the image of the service as written in the compose file, before the registry aliases are resolved
+    pub fn compose_image(&self, service: &str) -> Option<&str> {
+        self.compose.content.services.0.get(service)?.as_ref()?.image.as_deref()
+    }
+    /// the repository of the service's image as written in the compose file, `registry.example.com/app` of `registry.example.com/app:v2`
+    pub fn compose_repository(&self, service: &str) -> Option<&str> {
+        let image = self.compose_image(service)?;
+        let image = image.split_once('@').map_or(image, |(name, _)| name);
+        // a colon after the last slash starts the tag, before it is the registry port
+        Some(match image.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => name,
+            _ => image,
+        })
+    }
+    /// the registry that `host` names for this listener: its `host_aliases` are resolved first, then the `aliases` of `registries`
+    pub fn canonical_host<'a>(&'a self, host: &'a str) -> &'a str {
+        Config::global().canonical_host(self.aliased_host(host))
+    }
+    /// the host that `host` is an alias of in `host_aliases`, or `host` itself
+    fn aliased_host<'a>(&'a self, host: &'a str) -> &'a str {
+        self.host_aliases.iter()
+            .find(|(_, aliases)| aliases.iter().any(|alias| alias == host))
+            .map_or(host, |(aliased, _)| aliased)
+    }
+    /// pushes and cross-repository mounts are deployed and the other actions ignored unless configured in `on`
+    pub fn reaction(&self, action: EventAction) -> Reaction {
+        match self.on.get(&action) {
+            Some(reaction) => *reaction,
+            None if matches!(action, EventAction::Push | EventAction::Mount) => Reaction::Deploy,
+            None => Reaction::Ignore,
+        }
+    }
 }
 
 #[derive(Debug,Deserialize)]
@@ -70,9 +164,39 @@ pub struct Listener {
     #[serde(rename="compose_path",deserialize_with="deserialize_compose_with_path")]
     pub compose: ComposeWithPath,
     pub watch_services: Vec<String>,
-    /// Image to service mappings
+    /// seconds between registry checks of the watched images, for registries that can't send notifications
+    pub poll_interval: Option<u64>,
+    /// the chain run for the matched services when they are deployed, see `Listener::actions`
+    pub actions: Option<Vec<Action>>,
+    /// command run before the action chain, a failure aborts the deployment
+    pub pre_deploy: Option<Vec<String>>,
+    /// command run after the action chain with its outcome
+    pub post_deploy: Option<Vec<String>>,
+    /// the names of the `notifications` sent for this listener, all of them if missing
+    pub notify: Option<Vec<String>>,
+    /// what to do with the matched services for each event action, see `Listener::reaction`
+    #[serde(default="HashMap::default")]
+    pub on: HashMap<EventAction, Reaction>,
+    /// other hostnames of registry hosts for this listener only, like the `aliases` of `registries`, see `Listener::canonical_host`
+    #[serde(default="HashMap::default")]
+    pub host_aliases: HashMap<String, Vec<String>>,
+    /// Image to service mappings, the images are normalized
     #[serde(skip_deserializing,default="HashMap::default")]
-    pub itos: HashMap<String, String>
+    pub itos: HashMap<ImageRef, String>
+}
+
+#[derive(Debug,Deserialize,Serialize,Clone,Copy,PartialEq)]
+#[serde(rename_all="lowercase")]
+pub enum Reaction {
+    /// pull and restart the services
+    Deploy,
+    /// stop the services
+    Stop,
+    /// print a warning
+    Alert,
+    /// print the event
+    Log,
+    Ignore,
 }
 
 #[derive(Debug)]
@@ -91,15 +215,61 @@ impl Config {
             .unwrap_or_else(|err_msg| panic!("invalid configuration structure: {}", err_msg));
         // validation
         if config.server.port < 1024 { panic!("invalid configuration: invalid server port {}: cannot be less than 1024", config.server.port) }
+        if config.server.cors.as_ref().is_some_and(|cors| cors.allowed_origins.is_empty()) {
+            panic!("invalid configuration: server.cors.allowed_origins should have at least one origin")
+        }
+        for (host, registry) in config.registries.iter() {
+            if registry.username.is_some() != registry.password.is_some() {
+                panic!("invalid configuration: registry '{host}' should have both username and password or neither")
+            }
+            for alias in registry.aliases.iter() {
+                if config.registries.contains_key(alias) || config.registries.iter().any(|(other, r)| other != host && r.aliases.contains(alias)) {
+                    panic!("invalid configuration: alias '{alias}' of registry '{host}' names another registry")
+                }
+            }
+        }
+        for (name, notifier) in config.notifications.iter() {
+            for template in notifier.templates() {
+                if let Some(variable) = action::unknown_variables(template, &notify::VARIABLES).first() {
+                    panic!("invalid configuration: notification '{name}' uses the unknown variable '{{{{{variable}}}}}' in '{template}'")
+                }
+            }
+        }
         // if config.listeners.len() == 0 { panic!("invalid configuration: listeners must contain at least one element") }
         for (name, listener) in config.listeners.iter_mut() {
+            if listener.poll_interval == Some(0) {
+                panic!("invalid configuration: listener '{}' should have a poll_interval of at least 1 second", name)
+            }
+            for template in listener.actions.iter().flatten().flat_map(Action::templates) {
+                if let Some(variable) = action::unknown_variables(template, &[]).first() {
+                    panic!("invalid configuration: listener '{}' uses the unknown variable '{{{{{variable}}}}}' in '{template}'", name)
+                }
+            }
+            if [&listener.pre_deploy, &listener.post_deploy].into_iter().flatten().any(Vec::is_empty) {
+                panic!("invalid configuration: listener '{}' has an empty pre_deploy or post_deploy command", name)
+            }
+            if let Some(unknown) = listener.notify.iter().flatten().find(|notifier| !config.notifications.contains_key(*notifier)) {
+                panic!("invalid configuration: listener '{}' notifies the unknown notification '{unknown}'", name)
+            }
+            for (host, aliases) in listener.host_aliases.iter() {
+                if let Some(alias) = aliases.iter().find(|alias| listener.host_aliases.contains_key(*alias)) {
+                    panic!("invalid configuration: alias '{alias}' of host '{host}' in listener '{}' names another aliased host", name)
+                }
+            }
             if listener.watch_services.is_empty() {
                 panic!("invalid configuration: listener '{}' should have at least one watch_services defined", name)
             }
             for service_name in listener.watch_services.iter() {
                 match listener.compose.content.services.0.get(service_name) {
                     Some(Some(service)) => match &service.image {
-                        Some(image) => listener.itos.insert(image.into(), service_name.into()),
+                        // the images of aliased registries are stored with the canonical host
+                        Some(image) => match ImageRef::parse(image).and_then(|image| {
+                            let host = canonical_host(&config.registries, listener.aliased_host(&image.host())).to_owned();
+                            image.with_host(&host)
+                        }) {
+                            Ok(image) => listener.itos.insert(image, service_name.into()),
+                            Err(err) => panic!("invalid configuration: service '{service_name}' in compose file '{}': {err:#}", &listener.compose.path),
+                        },
                         None => panic!("invalid configuration: service '{service_name}' has no 'image' attribute in compose file '{}'", &listener.compose.path),
                     },
                     _ => panic!("invalid configuration: service '{service_name}' not found in compose file '{}'", &listener.compose.path),
@@ -115,6 +285,18 @@ impl Server {
     fn default() -> Self { serde_yaml::from_str::<Self>("").unwrap() }
     fn default_host() -> String { String::from("0.0.0.0") }
     fn default_port() -> u16 { 4463_u16 }
+    fn default_shutdown_timeout() -> u64 { 30 }
+}
+
+impl CorsConfig {
+    fn default_methods() -> Vec<String> { vec!["GET".into(), "HEAD".into(), "POST".into()] }
+    fn default_headers() -> Vec<String> { vec!["Authorization".into(), "Content-Type".into()] }
+}
+
+fn canonical_host<'a>(registries: &'a HashMap<String, RegistryConfig>, host: &'a str) -> &'a str {
+    registries.iter()
+        .find(|(_, registry)| registry.aliases.iter().any(|alias| alias == host))
+        .map_or(host, |(canonical, _)| canonical)
 }
 
 fn deserialize_compose_with_path<'de, D>(deserializer: D) -> std::result::Result<ComposeWithPath, D::Error> where D: Deserializer<'de> {
@@ -0,0 +1,128 @@
+use crate::{
+    compose::ComposeCmd,
+    config::{Config, Listener},
+    deploy::{DeployTarget, Deployer, JobKind},
+    http::wait_param,
+    prelude::*,
+    webhook::{EventAction, RegistryEvent},
+};
+use http_tokio::{ContentType, Json, Request, Response, StatusCode};
+use serde_json::{json, Value};
+
+/// The single page dashboard, it reads the JSON APIs below with the token it asks for
+const PAGE: &str = include_str!("dashboard.html");
+
+/// `GET /dashboard` and the `/api/` routes behind it
+pub async fn call(req: &Request, res: &mut Response) {
+    match req.matcher() {
+        ("GET", "/dashboard") => res.content_type(ContentType::Html).send(PAGE).await,
+        ("GET", "/api/listeners") => res.send(Json(json!({ "listeners": listeners().await }))).await,
+        ("GET", "/api/deployments") => res.send(Json(json!({ "deployments": Deployer::global().records() }))).await,
+        ("POST", path) => {
+            let action = path.strip_prefix("/api/listeners/").and_then(|path| path.rsplit_once('/'));
+            match action {
+                Some((name, "deploy")) => redeploy(req, res, name).await,
+                Some((name, "rollback")) => rollback(req, res, name).await,
+                _ => not_found(res, f!("no route for POST {path}")).await,
+            }
+        }
+        (method, path) => not_found(res, f!("no route for {method} {path}")).await,
+    }
+}
+
+/// The watched services of each listener with the digests of the images they run
+async fn listeners() -> Vec<Value> {
+    let mut listeners = vec![];
+    for (name, listener) in Config::global().listeners.iter() {
+        let compose = ComposeCmd::new(&listener.compose.path);
+        let mut services = vec![];
+        for service in listener.watch_services.iter() {
+            let image = listener.compose_image(service);
+            // a failed inspection is shown instead of failing the whole list
+            let (running, error) = match compose.running_digests(service).await {
+                Ok(digests) => (digests, None),
+                Err(err) => (vec![], Some(f!("{err:#}"))),
+            };
+            let health = compose.health(service).await.unwrap_or_default();
+            services.push(json!({ "service": service, "image": image, "running": running, "health": health, "error": error }));
+        }
+        listeners.push(json!({
+            "name": name,
+            "compose_path": listener.compose.path,
+            "poll_interval": listener.poll_interval,
+            "services": services,
+        }));
+    }
+    listeners
+}
+
+/// `POST /api/listeners/{name}/deploy` runs the action chain of the watched services,
+/// or only of the one named by `?service=`
+async fn redeploy(req: &Request, res: &mut Response, name: &str) {
+    let Some((name, listener)) = Config::global().listeners.get_key_value(name) else {
+        return not_found(res, f!("listener {name} not found")).await;
+    };
+    let services = match req.query("service") {
+        Some(service) if !listener.watch_services.contains(service) => {
+            return not_found(res, f!("listener {name} does not watch service {service}")).await;
+        }
+        Some(service) => vec![service.clone()],
+        None => listener.watch_services.clone(),
+    };
+    let targets = services.into_iter().filter_map(|service| manual_target(name, listener, service)).collect();
+    enqueue(req, res, JobKind::Deploy, targets).await
+}
+
+/// `POST /api/listeners/{name}/rollback` starts again the images replaced by the last deployment of the listener
+async fn rollback(req: &Request, res: &mut Response, name: &str) {
+    if !Config::global().listeners.contains_key(name) {
+        return not_found(res, f!("listener {name} not found")).await;
+    }
+    match Deployer::global().rollback_targets(name) {
+        Some(targets) if !targets.is_empty() => enqueue(req, res, JobKind::Rollback, targets).await,
+        _ => {
+            let error = json!({ "error": f!("no recent deployment of listener {name} to roll back") });
+            res.status(StatusCode::Conflict).send(Json(error)).await
+        }
+    }
+}
+
+/// A deployment of the service as if its tag was pushed again
+fn manual_target(name: &str, listener: &Listener, service: String) -> Option<DeployTarget> {
+    let (image, _) = listener.itos.iter().find(|(_, s)| **s == service)?;
+    Some(DeployTarget {
+        listener: name.to_owned(),
+        compose_path: listener.compose.path.clone(),
+        services: vec![service],
+        event: RegistryEvent {
+            action: EventAction::Push,
+            host: image.host(),
+            repository: image.path(),
+            tag: image.tag.clone(),
+            digest: None,
+            media_type: None,
+        },
+    })
+}
+
+/// Answers like the webhooks, with the record of the job or its outcome with `?wait=<seconds>`
+async fn enqueue(req: &Request, res: &mut Response, kind: JobKind, targets: Vec<DeployTarget>) {
+    let deployer = Deployer::global();
+    let id = match deployer.enqueue(kind, targets) {
+        Ok(id) => id,
+        Err(err) => {
+            let error = json!({ "error": f!("{err:#}") });
+            return res.status(StatusCode::ServiceUnavailable).send(Json(error)).await;
+        }
+    };
+    println!("- deployment #{id} queued from the dashboard");
+    let record = match wait_param(req) {
+        Some(wait) => deployer.wait(id, wait).await,
+        None => deployer.record(id),
+    };
+    res.send(Json(json!({ "deployments": Vec::from_iter(record) }))).await
+}
+
+async fn not_found(res: &mut Response, error: String) {
+    res.status(StatusCode::NotFound).send(Json(json!({ "error": error }))).await
+}
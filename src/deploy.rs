@@ -0,0 +1,537 @@
+use anyhow::Context;
+use crate::{
+    action::Variables,
+    activity::Activity,
+    compose::{CmdOutput, ComposeCmd},
+    config::Config,
+    hook::{self, HookResult, HookStage, Outcome},
+    image::ImageRef,
+    notify::{Notification, Status},
+    prelude::*,
+    webhook::{EventAction, RegistryEvent},
+};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{watch, Notify, OnceCell};
+use tokio::task::{self, JoinHandle};
+
+pub type ComposePath = String;
+pub type ServiceName = String;
+
+static DEPLOYER: OnceCell<Deployer> = OnceCell::const_new();
+
+/// Records of the finished deployments kept in memory
+const MAX_RECORDS: usize = 100;
+
+/// What a job does with its services
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobKind {
+    /// runs the action chain of the listeners
+    Deploy,
+    Stop,
+    /// pulls the images replaced by a deployment and starts them again
+    Rollback,
+}
+
+/// The services of a listener matched by an event
+#[derive(Debug)]
+pub struct DeployTarget {
+    pub listener: String,
+    pub compose_path: ComposePath,
+    pub services: Vec<ServiceName>,
+    pub event: RegistryEvent,
+}
+
+/// A deployment accepted from a registry notification
+#[derive(Debug)]
+pub struct DeployJob {
+    pub id: u64,
+    pub kind: JobKind,
+    pub targets: Vec<DeployTarget>,
+}
+
+impl DeployJob {
+    pub fn services(&self) -> Vec<&ServiceName> {
+        self.targets.iter().flat_map(|target| target.services.iter()).collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    /// a `pre_deploy` hook failed
+    Aborted,
+}
+
+impl JobState {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobState::Succeeded | JobState::Failed | JobState::Aborted)
+    }
+}
+
+/// The state of a deployment, its steps and the results of its hooks
+#[derive(Debug, Clone, Serialize)]
+pub struct DeployRecord {
+    pub id: u64,
+    pub kind: JobKind,
+    pub state: JobState,
+    pub services: Vec<ServiceName>,
+    pub error: Option<String>,
+    pub steps: Vec<StepRecord>,
+    pub hooks: Vec<HookResult>,
+    /// the images running before the deployment, to roll it back
+    pub replaced: Vec<RunningImage>,
+    /// incremented at each change, to stream only the changed records
+    #[serde(skip)]
+    pub revision: u64,
+}
+
+/// An action of a listener's chain, or the stop of its services
+#[derive(Debug, Clone, Serialize)]
+pub struct StepRecord {
+    pub listener: String,
+    pub action: String,
+    pub state: JobState,
+    pub error: Option<String>,
+    #[serde(flatten)]
+    pub output: CmdOutput,
+}
+
+/// The image a service container was running, by digest
+#[derive(Debug, Clone, Serialize)]
+pub struct RunningImage {
+    pub listener: String,
+    pub service: ServiceName,
+    /// as written in the compose file
+    pub image: String,
+    pub digest: String,
+}
+
+/// Runs the accepted deployments one at a time in a background worker.
+///
+/// needs to be started once with Deployer::start()
+pub struct Deployer {
+    queue: Mutex<VecDeque<DeployJob>>,
+    notify: Notify,
+    closed: AtomicBool,
+    next_id: AtomicU64,
+    running: Mutex<Option<u64>>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+    records: Mutex<VecDeque<DeployRecord>>,
+    /// bumped when a record changes
+    changes: watch::Sender<u64>,
+}
+
+/// What was left undone when the deployer was shut down
+#[derive(Debug)]
+pub struct ShutdownReport {
+    /// jobs that were accepted but never started
+    pub pending: Vec<DeployJob>,
+    /// the job that was still running when the timeout expired
+    pub interrupted: Option<u64>,
+}
+
+impl ShutdownReport {
+    pub fn is_clean(&self) -> bool {
+        self.pending.is_empty() && self.interrupted.is_none()
+    }
+}
+
+impl Deployer {
+    pub fn global() -> &'static Self {
+        DEPLOYER.get().expect("deployer is not started")
+    }
+
+    /// panics if the deployer was already started
+    pub fn start() {
+        let deployer = Self {
+            queue: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            closed: AtomicBool::new(false),
+            next_id: AtomicU64::new(1),
+            running: Mutex::new(None),
+            worker: Mutex::new(None),
+            records: Mutex::new(VecDeque::new()),
+            changes: watch::Sender::new(0),
+        };
+        if DEPLOYER.set(deployer).is_err() {
+            panic!("deployer is already started");
+        }
+        let handle = task::spawn(Self::global().work());
+        *Self::global().worker.lock().unwrap() = Some(handle);
+    }
+
+    /// Queues a deployment, returns its id or an error if the deployer is shutting down
+    pub fn enqueue(&self, kind: JobKind, targets: Vec<DeployTarget>) -> Result<u64> {
+        let mut queue = self.queue.lock().unwrap();
+        if self.closed.load(Ordering::SeqCst) {
+            anyhow::bail!("deployer is shutting down");
+        }
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let job = DeployJob { id, kind, targets };
+        let record = DeployRecord {
+            id,
+            kind,
+            state: JobState::Queued,
+            services: job.services().into_iter().cloned().collect(),
+            error: None,
+            steps: vec![],
+            hooks: vec![],
+            replaced: vec![],
+            revision: 0,
+        };
+        let mut records = self.records.lock().unwrap();
+        records.push_back(record);
+        if records.len() > MAX_RECORDS {
+            records.pop_front();
+        }
+        drop(records);
+        self.changes.send_modify(|changes| *changes += 1);
+        Activity::DeploymentQueued {
+            deployment: id,
+            kind,
+            services: job.services().into_iter().cloned().collect(),
+        }
+        .publish();
+        queue.push_back(job);
+        drop(queue);
+        self.notify.notify_one();
+        Ok(id)
+    }
+
+    /// Stops accepting jobs and waits up to `timeout` for the running one to finish
+    pub async fn shutdown(&self, timeout: Duration) -> ShutdownReport {
+        let pending = {
+            let mut queue = self.queue.lock().unwrap();
+            self.closed.store(true, Ordering::SeqCst);
+            queue.drain(..).collect::<Vec<_>>()
+        };
+        self.notify.notify_one();
+
+        let worker = self.worker.lock().unwrap().take();
+        let mut interrupted = None;
+        if let Some(worker) = worker {
+            if tokio::time::timeout(timeout, worker).await.is_err() {
+                interrupted = *self.running.lock().unwrap();
+            }
+        }
+        ShutdownReport { pending, interrupted }
+    }
+
+    /// The record of a recent deployment
+    pub fn record(&self, id: u64) -> Option<DeployRecord> {
+        self.records.lock().unwrap().iter().find(|record| record.id == id).cloned()
+    }
+
+    /// The recent deployments, newest first
+    pub fn records(&self) -> Vec<DeployRecord> {
+        self.records.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    /// Targets starting again the images replaced by the last deployment of the listener that ran its chain,
+    /// `None` if no recent deployment replaced any of them
+    pub fn rollback_targets(&self, listener: &str) -> Option<Vec<DeployTarget>> {
+        let compose_path = &Config::global().listeners.get(listener)?.compose.path;
+        let records = self.records.lock().unwrap();
+        let record = records.iter().rev().find(|record| {
+            // an aborted deployment did not replace the images
+            let ran = matches!(record.state, JobState::Succeeded | JobState::Failed);
+            record.kind == JobKind::Deploy && ran && record.replaced.iter().any(|running| running.listener == listener)
+        })?;
+        let targets = record
+            .replaced
+            .iter()
+            .filter(|running| running.listener == listener)
+            .filter_map(|running| {
+                let image = ImageRef::parse(&running.image).ok()?;
+                Some(DeployTarget {
+                    listener: listener.to_owned(),
+                    compose_path: compose_path.clone(),
+                    services: vec![running.service.clone()],
+                    event: RegistryEvent {
+                        action: EventAction::Push,
+                        host: image.host(),
+                        repository: image.path(),
+                        tag: image.tag.clone(),
+                        digest: Some(running.digest.clone()),
+                        media_type: None,
+                    },
+                })
+            })
+            .collect();
+        Some(targets)
+    }
+
+    /// Receives a new value when a record changes
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.changes.subscribe()
+    }
+
+    /// Waits up to `timeout` for the deployment to finish, returns its last record
+    pub async fn wait(&self, id: u64, timeout: Duration) -> Option<DeployRecord> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut changes = self.subscribe();
+        loop {
+            let record = self.record(id)?;
+            if record.state.is_finished() || tokio::time::timeout_at(deadline, changes.changed()).await.is_err() {
+                return Some(record);
+            }
+        }
+    }
+
+    fn update_record(&self, id: u64, update: impl FnOnce(&mut DeployRecord)) {
+        if let Some(record) = self.records.lock().unwrap().iter_mut().find(|record| record.id == id) {
+            update(record);
+            record.revision += 1;
+        }
+        self.changes.send_modify(|changes| *changes += 1);
+    }
+
+    /// Records a running step, returns its index to finish it with `finish_step`
+    fn start_step(&self, id: u64, listener: &str, action: &str) -> usize {
+        let mut index = 0;
+        self.update_record(id, |record| {
+            index = record.steps.len();
+            record.steps.push(StepRecord {
+                listener: listener.to_owned(),
+                action: action.to_owned(),
+                state: JobState::Running,
+                error: None,
+                output: CmdOutput::default(),
+            });
+        });
+        Activity::StepStarted {
+            deployment: id,
+            listener: listener.to_owned(),
+            action: action.to_owned(),
+        }
+        .publish();
+        index
+    }
+
+    /// Runs a command as a step, a command that could not be started fails the step like one that failed
+    async fn command_step(&self, id: u64, listener: &str, action: &str, command: &str, run: impl Future<Output = Result<CmdOutput>>) -> Result<()> {
+        let step = self.start_step(id, listener, action);
+        let (output, result) = match run.await {
+            Ok(output) => {
+                let result = output.check(command);
+                (output, result)
+            }
+            Err(err) => (CmdOutput::default(), Err(err)),
+        };
+        self.finish_step(id, step, output, &result);
+        result
+    }
+
+    fn finish_step(&self, id: u64, index: usize, output: CmdOutput, result: &Result<()>) {
+        let mut finished = None;
+        self.update_record(id, |record| {
+            if let Some(step) = record.steps.get_mut(index) {
+                step.state = if result.is_ok() { JobState::Succeeded } else { JobState::Failed };
+                step.error = result.as_ref().err().map(|err| f!("{err:#}"));
+                step.output = output;
+                finished = Some(Activity::StepFinished {
+                    deployment: id,
+                    listener: step.listener.clone(),
+                    action: step.action.clone(),
+                    state: step.state,
+                    exit_code: step.output.exit_code,
+                    error: step.error.clone(),
+                });
+            }
+        });
+        if let Some(activity) = finished {
+            activity.publish();
+        }
+    }
+
+    async fn work(&'static self) {
+        while let Some(job) = self.next_job().await {
+            *self.running.lock().unwrap() = Some(job.id);
+            self.update_record(job.id, |record| record.state = JobState::Running);
+            println!("- deployment #{} started", job.id);
+            let (state, error) = self.run_job(&job).await;
+            match &error {
+                None => println!("- deployment #{} completed", job.id),
+                Some(error) => eprintln!("deployment #{} finished as {state:?}: {error}", job.id),
+            }
+            self.update_record(job.id, |record| {
+                record.state = state;
+                record.error = error.clone();
+            });
+            Activity::DeploymentFinished { deployment: job.id, state, error }.publish();
+            *self.running.lock().unwrap() = None;
+        }
+    }
+
+    /// Runs every target even if another one failed, the job failed if any target failed
+    /// and was aborted if the others succeeded
+    async fn run_job(&self, job: &DeployJob) -> (JobState, Option<String>) {
+        let mut states = vec![];
+        let mut errors = vec![];
+        for target in job.targets.iter() {
+            let result = self.run_target(job, target).await;
+            let (state, status) = match &result {
+                Ok(()) => (JobState::Succeeded, Status::Succeeded),
+                Err(err) if err.is::<Aborted>() => (JobState::Aborted, Status::Aborted),
+                Err(_) => (JobState::Failed, Status::Failed),
+            };
+            let error = result.err().map(|err| {
+                eprintln!("deployment #{} of listener '{}' failed: {:?}", job.id, target.listener, err);
+                f!("{err:#}")
+            });
+            errors.extend(error.clone());
+            Notification::new(Some(job.id), status, target, error).send();
+            states.push(state);
+        }
+        let state = [JobState::Failed, JobState::Aborted]
+            .into_iter()
+            .find(|state| states.contains(state))
+            .unwrap_or(JobState::Succeeded);
+        (state, (!errors.is_empty()).then(|| errors.join("; ")))
+    }
+
+    async fn run_target(&self, job: &DeployJob, target: &DeployTarget) -> Result<()> {
+        let services = target.services.join(", ");
+        if job.kind == JobKind::Stop {
+            println!("- stopping services: [{services}] for compose '{}'", target.compose_path);
+            let compose = ComposeCmd::new(&target.compose_path);
+            let stop = compose.stop_services(&target.services);
+            self.command_step(job.id, &target.listener, "compose_stop", "docker compose stop", stop).await?;
+            println!("- services stopped");
+            return Ok(());
+        }
+        if job.kind == JobKind::Rollback {
+            return self.run_rollback(job, target).await;
+        }
+        println!("- detected services push: [{services}] for compose '{}'", target.compose_path);
+        let listener = &Config::global().listeners[&target.listener];
+        let vars = Variables::of(target);
+        let replaced = running_images(target).await;
+        self.update_record(job.id, |record| record.replaced.extend(replaced));
+        if let Some(command) = &listener.pre_deploy {
+            let result = self.run_hook(job.id, HookStage::PreDeploy, command, target, &vars, None).await;
+            if !result.output.success() {
+                self.run_post_hook(job.id, target, &vars, Outcome::Aborted).await;
+                return Err(anyhow::Error::new(Aborted(target.listener.clone())));
+            }
+        }
+        // the chain stops at the first failed action
+        let mut chain = Ok(());
+        for action in listener.actions().iter() {
+            let step = self.start_step(job.id, &target.listener, action.name());
+            let mut output = CmdOutput::default();
+            chain = action.run(target, &vars, &mut output).await.context(f!("action {} of listener '{}' failed", action.name(), target.listener));
+            self.finish_step(job.id, step, output, &chain);
+            if chain.is_err() {
+                break;
+            }
+            println!("- {} done", action.name());
+        }
+        let outcome = match chain {
+            Ok(()) => Outcome::Succeeded,
+            Err(_) => Outcome::Failed,
+        };
+        self.run_post_hook(job.id, target, &vars, outcome).await;
+        chain
+    }
+
+    /// Points the tag at the replaced digest again and restarts the service
+    async fn run_rollback(&self, job: &DeployJob, target: &DeployTarget) -> Result<()> {
+        let event = &target.event;
+        let (Some(tag), Some(digest)) = (&event.tag, &event.digest) else {
+            anyhow::bail!("no image to roll back to for listener '{}'", target.listener);
+        };
+        let name = f!("{}/{}", event.host, event.repository);
+        let pinned = f!("{name}@{digest}");
+        println!("- rolling back services: [{}] to {pinned}", target.services.join(", "));
+        let listener = &target.listener;
+        self.command_step(job.id, listener, "image_pull", "docker pull", ComposeCmd::pull_image(&pinned)).await?;
+        let tagged = f!("{name}:{tag}");
+        self.command_step(job.id, listener, "image_tag", "docker tag", ComposeCmd::tag_image(&pinned, &tagged)).await?;
+        let compose = ComposeCmd::new(&target.compose_path);
+        let up = compose.restart_services(&target.services);
+        self.command_step(job.id, listener, "compose_up", "docker compose up", up).await?;
+        println!("- services rolled back");
+        Ok(())
+    }
+
+    async fn run_post_hook(&self, id: u64, target: &DeployTarget, vars: &Variables, outcome: Outcome) {
+        if let Some(command) = &Config::global().listeners[&target.listener].post_deploy {
+            self.run_hook(id, HookStage::PostDeploy, command, target, vars, Some(outcome)).await;
+        }
+    }
+
+    async fn run_hook(&self, id: u64, stage: HookStage, command: &[String], target: &DeployTarget, vars: &Variables, outcome: Option<Outcome>) -> HookResult {
+        let result = hook::run(stage, command, target, vars, outcome).await;
+        match result.output.exit_code {
+            Some(0) => println!("- {stage} hook of listener '{}' done", target.listener),
+            Some(code) => eprintln!("{stage} hook of listener '{}' exited with {code}: {}", target.listener, result.output.stderr.trim()),
+            None => eprintln!("{stage} hook of listener '{}' failed: {}", target.listener, result.output.stderr.trim()),
+        }
+        self.update_record(id, |record| record.hooks.push(result.clone()));
+        Activity::HookFinished {
+            deployment: id,
+            listener: target.listener.clone(),
+            stage,
+            exit_code: result.output.exit_code,
+        }
+        .publish();
+        result
+    }
+
+    async fn next_job(&self) -> Option<DeployJob> {
+        loop {
+            if self.closed.load(Ordering::SeqCst) {
+                return None;
+            }
+            if let Some(job) = self.queue.lock().unwrap().pop_front() {
+                return Some(job);
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+/// The error of a deployment stopped by its `pre_deploy` hook
+#[derive(Debug)]
+struct Aborted(String);
+
+impl std::fmt::Display for Aborted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "pre_deploy hook of listener '{}' failed", self.0)
+    }
+}
+
+impl std::error::Error for Aborted {}
+
+/// The images the services of the target are running before it is deployed, a service that is
+/// not running or can't be inspected is left out
+async fn running_images(target: &DeployTarget) -> Vec<RunningImage> {
+    let listener = &Config::global().listeners[&target.listener];
+    let compose = ComposeCmd::new(&target.compose_path);
+    let mut images = vec![];
+    for service in target.services.iter() {
+        // the tag compose starts, to point it at the digest again on rollback
+        let Some(image) = listener.compose_image(service) else { continue };
+        match compose.running_digests(service).await {
+            Ok(digests) => images.extend(digests.into_iter().next().map(|digest| RunningImage {
+                listener: target.listener.clone(),
+                service: service.clone(),
+                image: image.to_owned(),
+                digest,
+            })),
+            Err(err) => eprintln!("could not inspect the running image of service '{service}': {err:#}"),
+        }
+    }
+    images
+}
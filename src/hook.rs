@@ -0,0 +1,88 @@
+use crate::{action::Variables, compose::{CmdOutput, ComposeCmd}, deploy::DeployTarget, prelude::*};
+use anyhow::Context;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::{
+    fmt::{self, Display},
+    process::Stdio,
+};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookStage {
+    PreDeploy,
+    PostDeploy,
+}
+
+impl Display for HookStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HookStage::PreDeploy => f.write_str("pre_deploy"),
+            HookStage::PostDeploy => f.write_str("post_deploy"),
+        }
+    }
+}
+
+/// How the action chain of a target ended, given to the `post_deploy` hook
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Succeeded,
+    Failed,
+    /// the `pre_deploy` hook failed and the chain did not run
+    Aborted,
+}
+
+/// A hook run, kept in the deployment record
+#[derive(Debug, Clone, Serialize)]
+pub struct HookResult {
+    pub listener: String,
+    pub stage: HookStage,
+    pub command: Vec<String>,
+    /// without exit code if the hook could not be started
+    #[serde(flatten)]
+    pub output: CmdOutput,
+}
+
+/// Runs a hook in the directory of the compose file with `COMPOSE_FILE` set, so that it can call `docker compose`.
+/// The variables of the event are passed as `DEPLOY_<NAME>` environment variables and as a JSON object on stdin
+pub async fn run(stage: HookStage, command: &[String], target: &DeployTarget, vars: &Variables, outcome: Option<Outcome>) -> HookResult {
+    let mut result = HookResult {
+        listener: target.listener.clone(),
+        stage,
+        command: command.to_vec(),
+        output: CmdOutput::default(),
+    };
+    match spawn(command, target, vars, outcome).await {
+        Ok(out) => result.output = out.into(),
+        Err(err) => result.output.stderr = f!("{err:#}"),
+    }
+    result
+}
+
+async fn spawn(command: &[String], target: &DeployTarget, vars: &Variables, outcome: Option<Outcome>) -> Result<std::process::Output> {
+    let Some((program, args)) = command.split_first() else { anyhow::bail!("empty hook command") };
+    let mut env = vars.iter().map(|(name, value)| (f!("DEPLOY_{}", name.to_uppercase()), value.to_owned())).collect::<Vec<_>>();
+    let mut payload = vars.iter().map(|(name, value)| (name.to_owned(), Value::from(value))).collect::<Map<_, _>>();
+    if let Some(outcome) = outcome {
+        let outcome = serde_json::to_value(outcome)?;
+        env.push(("DEPLOY_OUTCOME".into(), outcome.as_str().unwrap_or_default().into()));
+        payload.insert("outcome".into(), outcome);
+    }
+    let mut child = Command::new(program)
+        .args(args)
+        .envs(env)
+        .env("COMPOSE_FILE", &target.compose_path)
+        .current_dir(ComposeCmd::new(&target.compose_path).dir())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(f!("failed to run `{program}`"))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // a hook that doesn't read its stdin closes the pipe, that is not an error
+        let _ = stdin.write_all(&serde_json::to_vec(&payload)?).await;
+    }
+    Ok(child.wait_with_output().await?)
+}
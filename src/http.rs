@@ -1,82 +1,291 @@
-use crate::{compose::ComposeCmd, config::Config, prelude::*};
-use anyhow::Context;
-use http_tokio::{utils::print_request, Request, Response, StatusCode};
-use serde::Deserialize;
-use std::collections::HashMap;
-
-pub async fn handle_connection(req: Request, mut res: Response) {
-    print_request(&req);
-
-    if !authenticate(&req) {
-        unauthorized(&mut res).await
-    } else {
-        res.send_empty().await;
-        if let Err(err) = handle_registry_events(&req).await {
-            eprintln!("{:?}", err);
-            server_error(&mut res).await;
+use crate::{
+    activity::{Activity, ActivityFeed},
+    compose::ComposeCmd,
+    config::{Config, Listener, Reaction},
+    dashboard,
+    deploy::{DeployTarget, Deployer, JobKind},
+    image::ImageRef,
+    notify::{Notification, Status},
+    prelude::*,
+    registry::Registry,
+    webhook::{self, EventAction, RegistryEvent, WebhookFormat},
+};
+use http_tokio::{middleware::Cors, EventStream, Handler, Json, JsonError, Request, Response, ServerEvent, StatusCode};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::{sync::mpsc, task};
+
+/// Receives the registry notifications, logging and authentication are layered in `main`
+pub struct App;
+
+/// Longest wait of the `wait` query parameter, in seconds
+const MAX_WAIT: u64 = 600;
+/// Longest wait for the registry when checking that a pushed tag is current
+const DIGEST_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl Handler for App {
+    async fn call(&self, req: Request, res: &mut Response) {
+        if req.path == "/events" {
+            let events = ActivityFeed::global().subscribe();
+            return res.send(EventStream::new(events).keep_alive(Duration::from_secs(15))).await;
+        }
+        if req.path == "/dashboard" || req.path.starts_with("/api/") {
+            return dashboard::call(&req, res).await;
+        }
+        if let Some(id) = req.path.strip_prefix("/deployments/") {
+            return deployment(&req, res, id).await;
+        }
+        // `/webhooks/<format>` selects the payload format, any other path detects it
+        let format = match req.path.strip_prefix("/webhooks/") {
+            Some(name) => match WebhookFormat::from_name(name) {
+                Some(format) => Some(format),
+                None => return res.status(StatusCode::NotFound).send("404 NotFound").await,
+            },
+            None => None,
+        };
+        // a malformed notification is answered with 400 and the parse error
+        let payload = match req.json::<Value>() {
+            Ok(payload) => payload,
+            Err(err) => {
+                eprintln!("failed to parse registry request: {err}");
+                Activity::error(f!("failed to parse registry request: {err}")).publish();
+                return res.send(err).await;
+            }
+        };
+        let Some(format) = format.or_else(|| WebhookFormat::detect(&payload)) else {
+            eprintln!("unrecognized webhook payload: {payload}");
+            Activity::error("unrecognized webhook payload").publish();
+            let error = json!({ "error": "unrecognized webhook payload" });
+            return res.status(StatusCode::BadRequest).send(Json(error)).await;
+        };
+        let events = match format.parse(payload) {
+            Ok(events) => events,
+            Err(err) => {
+                eprintln!("failed to parse {format:?} webhook: {err}");
+                Activity::error(f!("failed to parse {format:?} webhook: {err}")).publish();
+                return res.send(JsonError::from(err)).await;
+            }
+        };
+        // the queued deployments, or their outcome when the caller waits for them with `?wait=<seconds>`
+        match handle_registry_events(events).await {
+            Ok(ids) => {
+                let mut records = vec![];
+                for id in ids {
+                    let record = match wait_param(&req) {
+                        Some(wait) => Deployer::global().wait(id, wait).await,
+                        None => Deployer::global().record(id),
+                    };
+                    records.extend(record);
+                }
+                res.send(Json(json!({ "deployments": records }))).await
+            }
+            Err(err) => {
+                eprintln!("{:?}", err);
+                Activity::error(f!("{err:#}")).publish();
+                server_error(res).await;
+            }
         }
     }
 }
 
-type ComposePath = String;
-type ServiceName = String;
+/// `GET /deployments/{id}` answers the record of the deployment. With `?wait=<seconds>` it answers once the
+/// deployment is finished or the time passed, with `Accept: text/event-stream` it streams the record at each change
+async fn deployment(req: &Request, res: &mut Response, id: &str) {
+    let Some(record) = id.parse::<u64>().ok().and_then(|id| Deployer::global().record(id)) else {
+        let error = json!({ "error": f!("deployment {id} not found") });
+        return res.status(StatusCode::NotFound).send(Json(error)).await;
+    };
+    if req.header("Accept").is_some_and(|accept| accept.contains("text/event-stream")) {
+        let events = stream_record(record.id);
+        return res.send(EventStream::new(events).keep_alive(Duration::from_secs(15))).await;
+    }
+    let record = match wait_param(req) {
+        Some(wait) => Deployer::global().wait(record.id, wait).await.unwrap_or(record),
+        None => record,
+    };
+    res.send(Json(record)).await
+}
+
+/// `progress` events with the changed record, then `done` when it is finished
+fn stream_record(id: u64) -> mpsc::Receiver<ServerEvent> {
+    let (events, receiver) = mpsc::channel(16);
+    task::spawn(async move {
+        let deployer = Deployer::global();
+        let mut changes = deployer.subscribe();
+        let mut revision = None;
+        loop {
+            let Some(record) = deployer.record(id) else { return };
+            if revision != Some(record.revision) {
+                revision = Some(record.revision);
+                let finished = record.state.is_finished();
+                let data = serde_json::to_string(&record).unwrap_or_default();
+                let event = ServerEvent::new(data).event(if finished { "done" } else { "progress" }).id(record.revision.to_string());
+                let sent = events.send(event).await;
+                if finished || sent.is_err() {
+                    return;
+                }
+            }
+            tokio::select! {
+                changed = changes.changed() => if changed.is_err() { return },
+                _ = events.closed() => return,
+            }
+        }
+    });
+    receiver
+}
 
-async fn handle_registry_events(req: &Request) -> Result<()> {
-    let body: RegistryWebhookRequest =
-        serde_json::from_str(&req.body).context("failed to parse registry request")?;
+pub fn wait_param(req: &Request) -> Option<Duration> {
+    let wait = req.query("wait")?.parse::<u64>().ok()?;
+    Some(Duration::from_secs(wait.min(MAX_WAIT)))
+}
 
-    eprintln!("REQUEST {:?}", body);
+/// Queues the reactions of the listeners to the events, returns the ids of the queued jobs
+pub async fn handle_registry_events(events: Vec<RegistryEvent>) -> Result<Vec<u64>> {
+    let events = webhook::top_level(events);
 
-    let mut updated_compose = HashMap::<ComposePath, Vec<ServiceName>>::new();
-    let mut possibly_dangling_images: Vec<String> = vec![];
-    for event in body.events.iter().filter(|e| e.action.eq("push")) {
-        let pushed_image = f!("{}/{}", event.request.host, event.target.repository);
-        eprintln!("PUSHED IMAGE {}", pushed_image);
-        if Config::global().remove_dangling {
-            possibly_dangling_images.push(pushed_image.clone());
+    let mut deployed = Vec::<DeployTarget>::new();
+    let mut stopped = Vec::<DeployTarget>::new();
+    // the registry lookups of a large batch run together, so that the notification is answered in time
+    let checks = events.iter().map(|event| task::spawn(is_current(event.clone()))).collect::<Vec<_>>();
+    for (event, check) in events.iter().zip(checks) {
+        Activity::registry_event(event).publish();
+        let pushed_image = f!("{}/{}", Config::global().canonical_host(&event.host), event.repository);
+        if !check.await.unwrap_or(true) {
+            eprintln!("skipping {pushed_image}: tag {} was pushed again since this notification", event.tag.as_deref().unwrap_or_default());
+            continue;
         }
-        for listener in Config::global().listeners.iter() {
-            let listener = listener.1;
-            eprintln!("checking listener for compose: {}", listener.compose.path);
-            eprintln!("services listening: {:?}", listener.itos);
-            let service = match listener.itos.get(&pushed_image) {
+        for (name, listener) in Config::global().listeners.iter() {
+            let service = match listening_service(listener, event).await {
                 Some(s) => s,
                 None => continue,
             };
-            eprintln!("FOUND SERVICE: {:?}", listener.watch_services);
-            updated_compose
-                .entry((&listener.compose.path).into())
-                .or_insert(Vec::new())
-                .push(service.into());
+            let tag = event.tag.as_deref().unwrap_or_default();
+            let reaction = listener.reaction(event.action);
+            Activity::ListenerMatch {
+                listener: name.clone(),
+                service: service.clone(),
+                action: event.action,
+                reaction,
+            }
+            .publish();
+            let targets = match reaction {
+                Reaction::Deploy => &mut deployed,
+                Reaction::Stop => &mut stopped,
+                Reaction::Alert => {
+                    eprintln!("ALERT listener '{name}': {} of {pushed_image}:{tag} used by service '{service}'", event.action);
+                    let target = DeployTarget {
+                        listener: name.clone(),
+                        compose_path: listener.compose.path.clone(),
+                        services: vec![service.clone()],
+                        event: event.clone(),
+                    };
+                    Notification::new(None, Status::Alert, &target, None).send();
+                    continue;
+                }
+                Reaction::Log => {
+                    println!("- listener '{name}': {} of {pushed_image}:{tag} used by service '{service}'", event.action);
+                    continue;
+                }
+                Reaction::Ignore => continue,
+            };
+            // a mount and the push of the same image name the same service
+            if targets.iter().any(|target| target.listener == *name && target.services.contains(service)) {
+                continue;
+            }
+            targets.push(DeployTarget {
+                listener: name.clone(),
+                compose_path: listener.compose.path.clone(),
+                services: vec![service.clone()],
+                event: event.clone(),
+            });
         }
     }
-    if updated_compose.is_empty() {
-        return Ok(());
+    let mut ids = vec![];
+    if !stopped.is_empty() {
+        let id = Deployer::global().enqueue(JobKind::Stop, stopped)?;
+        println!("- stop #{id} queued");
+        ids.push(id);
     }
+    if !deployed.is_empty() {
+        let id = Deployer::global().enqueue(JobKind::Deploy, deployed)?;
+        println!("- deployment #{id} queued");
+        ids.push(id);
+    }
+    Ok(ids)
+}
 
-    for (compose_path, services) in updated_compose.iter() {
-        let docker_compose = ComposeCmd::new(compose_path);
-        println!(
-            "- detected services push: [{}] for compose '{}'",
-            services.join(", "),
-            compose_path
-        );
-        docker_compose.pull_services(&services).await?;
-        println!("- services pulled");
-        docker_compose.restart_services(&services).await?;
-        println!("- services restarted");
+/// The service of the listener the event is about: the one using the pushed tag, or for the tagless
+/// mounts and deletes the one running the digest
+async fn listening_service<'a>(listener: &'a Listener, event: &RegistryEvent) -> Option<&'a String> {
+    let image = event_image(listener, event)?;
+    if image.tag.is_some() {
+        return listener.itos.get(&image);
     }
+    let digest = event.digest.as_ref()?;
+    let compose = ComposeCmd::new(&listener.compose.path);
+    for service in repository_services(listener, &image) {
+        match compose.running_digests(service).await {
+            Ok(running) if running.contains(digest) => return Some(service),
+            Ok(_) => {}
+            Err(err) => eprintln!("could not inspect the running image of service '{service}': {err:#}"),
+        }
+    }
+    None
+}
 
-    if Config::global().remove_dangling {
-        for image in possibly_dangling_images {
-            ComposeCmd::clean_dangling(&image).await?;
+/// The services of the listener using any tag of the image's repository
+fn repository_services<'a>(listener: &'a Listener, image: &ImageRef) -> Vec<&'a String> {
+    listener.itos.iter().filter(|(watched, _)| watched.name() == image.name()).map(|(_, service)| service).collect()
+}
+
+/// The event's image as a normalized reference with the canonical host of the listener, without tag for the tagless events
+fn event_image(listener: &Listener, event: &RegistryEvent) -> Option<ImageRef> {
+    let host = listener.canonical_host(&event.host);
+    let reference = match &event.tag {
+        Some(tag) => f!("{host}/{}:{tag}", event.repository),
+        None => f!("{host}/{}", event.repository),
+    };
+    match ImageRef::parse(&reference) {
+        // the parser defaults to `latest`
+        Ok(image) => Some(ImageRef { tag: event.tag.clone(), ..image }),
+        Err(err) => {
+            eprintln!("ignoring pushed image: {err:#}");
+            Activity::error(f!("ignoring pushed image: {err:#}")).publish();
+            None
         }
     }
+}
 
-    Ok(())
+/// Whether the tag still points to the pushed manifest, so that stale or replayed notifications
+/// don't redeploy. Only checked on the registries configured in `registries`, a failed or slow lookup is not blocking
+async fn is_current(event: RegistryEvent) -> bool {
+    // only the actions that point the tag at a new manifest can be stale
+    if !matches!(event.action, EventAction::Push | EventAction::Mount) {
+        return true;
+    }
+    let (Some(tag), Some(digest)) = (&event.tag, &event.digest) else { return true };
+    if !Registry::is_configured(&event.host) {
+        return true;
+    }
+    let registry = Registry::new(&event.host);
+    match tokio::time::timeout(DIGEST_CHECK_TIMEOUT, registry.digest(&event.repository, tag)).await {
+        Ok(Ok(current)) => current.eq(digest),
+        Ok(Err(err)) => {
+            eprintln!("could not verify the pushed digest: {err:#}");
+            true
+        }
+        Err(_) => {
+            eprintln!("could not verify the pushed digest: the registry did not answer in {}s", DIGEST_CHECK_TIMEOUT.as_secs());
+            true
+        }
+    }
 }
 
-fn authenticate(req: &Request) -> bool {
+pub fn authenticate(req: &Request) -> bool {
+    // the dashboard page holds no data, it asks for the token of the APIs
+    if req.matcher() == ("GET", "/dashboard") {
+        return true;
+    }
     let auth = match &Config::global().server.auth_token {
         None => return true,
         Some(token) => f!("Bearer {token}"),
@@ -85,6 +294,20 @@ fn authenticate(req: &Request) -> bool {
         .map_or(false, |auth_header| auth_header.eq(&auth))
 }
 
+/// The CORS layer configured in `server.cors`
+pub fn cors() -> Option<Cors> {
+    let config = Config::global().server.cors.as_ref()?;
+    let mut cors = Cors::new(&config.allowed_origins)
+        .allow_methods(&config.allowed_methods)
+        .allow_headers(&config.allowed_headers)
+        .expose_headers(&config.exposed_headers)
+        .allow_credentials(config.allow_credentials);
+    if let Some(max_age) = config.max_age {
+        cors = cors.max_age(Duration::from_secs(max_age));
+    }
+    Some(cors)
+}
+
 // default responses
 async fn server_error(res: &mut Response) {
     res.status(StatusCode::InternalServerError)
@@ -92,42 +315,88 @@ async fn server_error(res: &mut Response) {
         .await;
 }
 
-async fn unauthorized(res: &mut Response) {
-    res.status(StatusCode::Unauthorized)
-        .send("401 Unauthorized")
-        .await;
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ComposeWithPath;
+    use docker_compose_types::Compose;
+    use std::collections::HashMap;
 
-// body
-#[derive(Deserialize, Debug)]
-#[allow(unused)]
-struct RegistryWebhookRequest {
-    events: Vec<RegistryEvent>,
-}
+    /// `web` runs `10.0.0.5:5000/app:v2`
+    fn listener(on: &[(EventAction, Reaction)]) -> Listener {
+        Config::init_test();
+        Listener {
+            compose: ComposeWithPath { path: "/tmp/compose.yml".into(), content: Compose::default() },
+            watch_services: vec!["web".into()],
+            poll_interval: None,
+            actions: None,
+            pre_deploy: None,
+            post_deploy: None,
+            notify: None,
+            on: on.iter().copied().collect(),
+            host_aliases: HashMap::new(),
+            itos: HashMap::from([(ImageRef::parse("10.0.0.5:5000/app:v2").unwrap(), "web".into())]),
+        }
+    }
 
-#[derive(Deserialize, Debug)]
-#[allow(unused)]
-struct RegistryEvent {
-    id: String,
-    timestamp: String,
-    action: String,
-    target: RegistryEventTarget,
-    request: RegistryEventRequest,
-}
+    fn event(action: EventAction, repository: &str, tag: Option<&str>) -> RegistryEvent {
+        RegistryEvent {
+            action,
+            host: "registry.example.com".into(),
+            repository: repository.into(),
+            tag: tag.map(String::from),
+            digest: Some("sha256:abc".into()),
+            media_type: None,
+        }
+    }
 
-#[derive(Deserialize, Debug)]
-#[allow(unused)]
-struct RegistryEventTarget {
-    repository: String,
-    tag: String,
-}
+    #[tokio::test]
+    async fn tagged_events_match_the_service_using_the_tag() {
+        let listener = listener(&[]);
+        for action in [EventAction::Push, EventAction::Delete, EventAction::Mount] {
+            let service = listening_service(&listener, &event(action, "app", Some("v2"))).await;
+            assert_eq!(service.map(String::as_str), Some("web"), "{action}");
+        }
+        assert_eq!(listening_service(&listener, &event(EventAction::Push, "app", Some("v3"))).await, None);
+        assert_eq!(listening_service(&listener, &event(EventAction::Push, "other", Some("v2"))).await, None);
+    }
+
+    #[tokio::test]
+    async fn listener_aliases_resolve_before_the_registry_aliases() {
+        let mut listener = listener(&[]);
+        listener.host_aliases = HashMap::from([("registry.example.com".into(), vec!["push.internal".into()])]);
+        let pushed = RegistryEvent { host: "push.internal".into(), ..event(EventAction::Push, "app", Some("v2")) };
+        assert_eq!(listening_service(&listener, &pushed).await.map(String::as_str), Some("web"));
+        assert_eq!(listener.canonical_host("push.internal"), "10.0.0.5:5000");
+        assert_eq!(listener.canonical_host("other.example.com"), "other.example.com");
+    }
 
-#[derive(Deserialize, Debug)]
-#[allow(unused)]
-struct RegistryEventRequest {
-    id: String,
-    addr: String,
-    host: String,
-    method: String,
-    useragent: String,
+    #[test]
+    fn tagless_mounts_and_deletes_are_matched_by_repository() {
+        let listener = listener(&[]);
+        for action in [EventAction::Mount, EventAction::Delete] {
+            let image = event_image(&listener, &event(action, "app", None)).unwrap();
+            assert_eq!(image.tag, None);
+            assert_eq!(repository_services(&listener, &image), vec!["web"]);
+        }
+        let other = event_image(&listener, &event(EventAction::Mount, "other", None)).unwrap();
+        assert!(repository_services(&listener, &other).is_empty());
+    }
+
+    #[test]
+    fn mounts_are_deployed_unless_configured() {
+        let listener = listener(&[]);
+        assert_eq!(listener.reaction(EventAction::Push), Reaction::Deploy);
+        assert_eq!(listener.reaction(EventAction::Mount), Reaction::Deploy);
+        assert_eq!(listener.reaction(EventAction::Delete), Reaction::Ignore);
+        assert_eq!(listener.reaction(EventAction::Pull), Reaction::Ignore);
+    }
+
+    #[test]
+    fn configured_reactions_replace_the_defaults() {
+        let listener = listener(&[(EventAction::Delete, Reaction::Stop), (EventAction::Mount, Reaction::Log)]);
+        assert_eq!(listener.reaction(EventAction::Delete), Reaction::Stop);
+        assert_eq!(listener.reaction(EventAction::Mount), Reaction::Log);
+        assert_eq!(listener.reaction(EventAction::Push), Reaction::Deploy);
+    }
 }
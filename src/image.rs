@@ -0,0 +1,206 @@
+use crate::prelude::*;
+use anyhow::{bail, Context};
+use std::fmt::{self, Display};
+
+const DEFAULT_REGISTRY: &str = "docker.io";
+const DEFAULT_TAG: &str = "latest";
+
+/// An image reference normalized with Docker's rules, so that `nginx`, `docker.io/library/nginx`
+/// and `index.docker.io/library/nginx:latest` are equal
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ImageRef {
+    /// registry host without the port, `docker.io` when the reference names none
+    pub registry: String,
+    pub port: Option<u16>,
+    /// the path before the last component, `library` for Docker Hub official images
+    pub namespace: Option<String>,
+    pub repository: String,
+    /// `latest` when the reference has neither a tag nor a digest
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+impl ImageRef {
+    pub fn parse(reference: &str) -> Result<Self> {
+        let (rest, digest) = match reference.split_once('@') {
+            Some((rest, digest)) => (rest, Some(digest)),
+            None => (reference, None),
+        };
+        // a colon after the last slash starts the tag, before it is the registry port
+        let (rest, tag) = match rest.rsplit_once(':') {
+            Some((rest, tag)) if !tag.contains('/') => (rest, Some(tag)),
+            _ => (rest, None),
+        };
+        let (domain, path) = match rest.split_once('/') {
+            Some((domain, path)) if domain.contains(['.', ':']) || domain == "localhost" => (domain, path),
+            _ => (DEFAULT_REGISTRY, rest),
+        };
+        let domain = if domain == "index.docker.io" { DEFAULT_REGISTRY } else { domain };
+        let path = match domain == DEFAULT_REGISTRY && !path.contains('/') {
+            true => f!("library/{path}"),
+            false => path.to_owned(),
+        };
+        let (registry, port) = split_host(domain).context(f!("invalid registry in `{reference}`"))?;
+        if !path.split('/').all(is_path_component) {
+            bail!("invalid repository `{path}` in `{reference}`");
+        }
+        if let Some(tag) = tag.filter(|tag| !is_tag(tag)) {
+            bail!("invalid tag `{tag}` in `{reference}`");
+        }
+        if let Some(digest) = digest.filter(|digest| !is_digest(digest)) {
+            bail!("invalid digest `{digest}` in `{reference}`");
+        }
+        let (namespace, repository) = match path.rsplit_once('/') {
+            Some((namespace, repository)) => (Some(namespace.to_owned()), repository.to_owned()),
+            None => (None, path),
+        };
+        Ok(Self {
+            registry,
+            port,
+            namespace,
+            repository,
+            tag: tag.or(digest.is_none().then_some(DEFAULT_TAG)).map(String::from),
+            digest: digest.map(String::from),
+        })
+    }
+
+    /// The same image on another registry host
+    pub fn with_host(self, host: &str) -> Result<Self> {
+        let (registry, port) = split_host(host)?;
+        Ok(Self { registry, port, ..self })
+    }
+
+    /// The registry as it is addressed, with the port
+    pub fn host(&self) -> String {
+        match self.port {
+            Some(port) => f!("{}:{port}", self.registry),
+            None => self.registry.clone(),
+        }
+    }
+
+    /// The repository path in the registry, with the namespace
+    pub fn path(&self) -> String {
+        match &self.namespace {
+            Some(namespace) => f!("{namespace}/{}", self.repository),
+            None => self.repository.clone(),
+        }
+    }
+
+    /// The reference without tag and digest
+    pub fn name(&self) -> String {
+        f!("{}/{}", self.host(), self.path())
+    }
+}
+
+impl Display for ImageRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{tag}")?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{digest}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits `host:port`, IPv6 addresses keep their brackets
+fn split_host(host: &str) -> Result<(String, Option<u16>)> {
+    let (registry, port) = match host.rsplit_once(':') {
+        Some((registry, port)) if !port.ends_with(']') => (registry, Some(port.parse::<u16>().context(f!("invalid port `{port}`"))?)),
+        _ => (host, None),
+    };
+    if registry.is_empty() {
+        bail!("missing registry host");
+    }
+    Ok((registry.to_owned(), port))
+}
+
+/// lowercase alphanumerics joined by `.`, `_`, `__` or dashes
+fn is_path_component(component: &str) -> bool {
+    let bytes = component.as_bytes();
+    !bytes.is_empty()
+        && bytes.iter().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"._-".contains(b))
+        && bytes[0].is_ascii_alphanumeric()
+        && bytes[bytes.len() - 1].is_ascii_alphanumeric()
+}
+
+fn is_tag(tag: &str) -> bool {
+    tag.len() <= 128
+        && tag.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+        && tag.bytes().all(|b| b.is_ascii_alphanumeric() || b"_.-".contains(&b))
+}
+
+/// `algorithm:hex`, like `sha256:...`
+fn is_digest(digest: &str) -> bool {
+    match digest.split_once(':') {
+        Some((algorithm, encoded)) => {
+            !algorithm.is_empty()
+                && algorithm.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"+._-".contains(&b))
+                && encoded.len() >= 32
+                && encoded.bytes().all(|b| b.is_ascii_hexdigit())
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn references_are_normalized() {
+        let digested = f!("nginx@{DIGEST}");
+        let tagged_digested = f!("registry.example.com/app:v2@{DIGEST}");
+        // reference, host, path, tag, digest
+        let table = [
+            ("nginx", "docker.io", "library/nginx", Some("latest"), None),
+            ("nginx:1.27", "docker.io", "library/nginx", Some("1.27"), None),
+            ("docker.io/library/nginx", "docker.io", "library/nginx", Some("latest"), None),
+            ("index.docker.io/library/nginx:latest", "docker.io", "library/nginx", Some("latest"), None),
+            ("bitnami/redis", "docker.io", "bitnami/redis", Some("latest"), None),
+            ("ghcr.io/owner/app", "ghcr.io", "owner/app", Some("latest"), None),
+            ("localhost/app", "localhost", "app", Some("latest"), None),
+            ("localhost:5000/app:dev", "localhost:5000", "app", Some("dev"), None),
+            ("10.0.0.5:5000/team/app:v2", "10.0.0.5:5000", "team/app", Some("v2"), None),
+            (digested.as_str(), "docker.io", "library/nginx", None, Some(DIGEST)),
+            (tagged_digested.as_str(), "registry.example.com", "app", Some("v2"), Some(DIGEST)),
+        ];
+        for (reference, host, path, tag, digest) in table {
+            let image = ImageRef::parse(reference).unwrap();
+            assert_eq!(
+                (image.host().as_str(), image.path().as_str(), image.tag.as_deref(), image.digest.as_deref()),
+                (host, path, tag, digest),
+                "{reference}"
+            );
+        }
+    }
+
+    #[test]
+    fn equal_references_parse_equal() {
+        let nginx = ImageRef::parse("nginx").unwrap();
+        assert_eq!(ImageRef::parse("docker.io/library/nginx").unwrap(), nginx);
+        assert_eq!(ImageRef::parse("index.docker.io/library/nginx:latest").unwrap(), nginx);
+        assert_ne!(ImageRef::parse("nginx:1.27").unwrap(), nginx);
+        assert_eq!(nginx.to_string(), "docker.io/library/nginx:latest");
+    }
+
+    #[test]
+    fn invalid_references_are_rejected() {
+        for reference in ["", "App", "registry.example.com:port/app", "app:-v2", "app@sha256:abc", "registry.example.com//app"] {
+            assert!(ImageRef::parse(reference).is_err(), "{reference}");
+        }
+    }
+
+    #[test]
+    fn aliases_keep_the_repository_and_tag() {
+        let image = ImageRef::parse("registry.example.com/team/app:v2").unwrap();
+        let aliased = image.clone().with_host("10.0.0.5:5000").unwrap();
+        assert_eq!(aliased, ImageRef::parse("10.0.0.5:5000/team/app:v2").unwrap());
+        assert_eq!(aliased.with_host("registry.example.com").unwrap(), image);
+        assert!(image.with_host(":5000").is_err());
+    }
+}
@@ -1,21 +1,38 @@
+mod action;
+mod activity;
 mod compose;
 mod config;
+mod dashboard;
+mod deploy;
+mod hook;
 mod http;
+mod image;
+mod notify;
+mod poll;
 mod prelude;
+mod registry;
+mod webhook;
 
-use crate::config::Config;
+use crate::{activity::ActivityFeed, config::Config, deploy::Deployer};
 use anyhow::Context;
-use http_tokio::utils::accept_connection;
+use http_tokio::{
+    middleware::{Auth, Logger, RequestId},
+    serve, Handler, Metrics,
+};
 pub use prelude::*;
-use tokio::{net::TcpListener, task};
+use std::process::ExitCode;
+use tokio::{
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+};
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     Config::init().await;
 
     if Config::global().test_mode {
         println!("the configuration is fine!");
-        return Ok(());
+        return Ok(ExitCode::SUCCESS);
     }
 
     let addr = Config::global().server.address();
@@ -24,9 +41,41 @@ async fn main() -> Result<()> {
         .context(f!("could not start server at {addr}"))?;
     println!("server listening on {addr}");
 
-    loop {
-        if let Ok((req, res)) = accept_connection(&server).await {
-            task::spawn(async { http::handle_connection(req, res).await });
-        }
+    ActivityFeed::start();
+    Deployer::start();
+    poll::start();
+
+    let app = http::App
+        .layer(Auth::new(http::authenticate))
+        // preflight requests carry no credentials, they are answered before authentication
+        .layer(http::cors())
+        .layer(RequestId::new())
+        .layer(Logger);
+    let mut sigterm = signal(SignalKind::terminate()).context("could not listen for SIGTERM")?;
+    // the listener is dropped with `serve`, no connection is accepted while waiting for the deployments
+    tokio::select! {
+        _ = serve(server, app) => {}
+        _ = sigterm.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    let metrics = Metrics::global();
+    println!("served {} requests, {} handler panics", metrics.requests(), metrics.panics());
+    let timeout = Config::global().server.shutdown_timeout();
+    println!("shutting down, waiting up to {}s for running deployments", timeout.as_secs());
+
+    let report = Deployer::global().shutdown(timeout).await;
+    if let Some(id) = report.interrupted {
+        eprintln!("deployment #{id} did not finish in time and was interrupted");
+    }
+    for job in report.pending.iter() {
+        let services = job.services().into_iter().cloned().collect::<Vec<_>>();
+        eprintln!("deployment #{} was never started: [{}]", job.id, services.join(", "));
+    }
+
+    if report.is_clean() {
+        println!("shutdown complete");
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::FAILURE)
     }
 }
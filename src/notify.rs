@@ -0,0 +1,394 @@
+use crate::{action::Variables, activity::Activity, config::Config, deploy::DeployTarget, prelude::*};
+use anyhow::{bail, Context};
+use http_tokio::{Client, ClientError, ClientResponse};
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    process::Stdio,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{io::AsyncWriteExt, process::Command, task};
+
+/// The names usable in the notification templates besides the action variables
+pub const VARIABLES: [&str; 3] = ["deployment", "status", "error"];
+
+const DEFAULT_TEMPLATE: &str = "[{{status}}] {{listener}}: {{action}} of {{image}} for {{services}} {{error}}";
+const DEFAULT_SUBJECT: &str = "[{{status}}] {{image}}";
+/// Longest wait between two delivery attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// What a notification reports, the sinks subscribe to them with `on`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Succeeded,
+    Failed,
+    Aborted,
+    /// an event of a listener configured with `alert` in `on`
+    Alert,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self {
+            Status::Succeeded => "succeeded",
+            Status::Failed => "failed",
+            Status::Aborted => "aborted",
+            Status::Alert => "alert",
+        };
+        f.write_str(status)
+    }
+}
+
+/// A sink of the `notifications` configuration
+#[derive(Debug, Deserialize)]
+pub struct Notifier {
+    #[serde(flatten)]
+    pub sink: Sink,
+    /// the message, `DEFAULT_TEMPLATE` if missing
+    pub template: Option<String>,
+    #[serde(default = "Notifier::default_on")]
+    pub on: Vec<Status>,
+    /// attempts after the first failed delivery
+    #[serde(default = "Notifier::default_retries")]
+    pub retries: u32,
+    /// seconds before the first retry, doubled at each attempt up to `MAX_BACKOFF`
+    #[serde(default = "Notifier::default_backoff")]
+    pub backoff: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Sink {
+    /// POST of the notification as JSON
+    Webhook {
+        url: String,
+        #[serde(default = "HashMap::default")]
+        headers: HashMap<String, String>,
+    },
+    /// Slack or Mattermost incoming webhook
+    Slack { url: String },
+    Email {
+        smtp_host: String,
+        smtp_port: Option<u16>,
+        #[serde(default = "SmtpTls::default")]
+        tls: SmtpTls,
+        username: Option<String>,
+        password: Option<String>,
+        from: String,
+        to: Vec<String>,
+        subject: Option<String>,
+    },
+    /// a local program, the notification is passed as JSON on stdin
+    Command { command: Vec<String> },
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    #[default]
+    Starttls,
+    /// implicit TLS, usually on port 465
+    Tls,
+    None,
+}
+
+/// A deployment result or alert, sent to the notifiers of its listener
+#[derive(Debug, Serialize)]
+pub struct Notification {
+    pub deployment: Option<u64>,
+    pub status: Status,
+    pub listener: String,
+    pub services: Vec<String>,
+    pub error: Option<String>,
+    /// the action variables of the event
+    pub event: Map<String, Value>,
+    #[serde(skip)]
+    vars: Variables,
+}
+
+impl Notifier {
+    fn default_on() -> Vec<Status> { vec![Status::Succeeded, Status::Failed, Status::Aborted, Status::Alert] }
+    fn default_retries() -> u32 { 3 }
+    fn default_backoff() -> u64 { 2 }
+
+    /// The templates of the notifier, checked when the configuration is loaded
+    pub fn templates(&self) -> Vec<&String> {
+        let subject = match &self.sink {
+            Sink::Email { subject, .. } => subject.as_ref(),
+            _ => None,
+        };
+        self.template.iter().chain(subject).collect()
+    }
+
+    async fn deliver(&self, notification: &Notification) -> Result<()> {
+        let message = notification.vars.render(self.template.as_deref().unwrap_or(DEFAULT_TEMPLATE)).trim().to_owned();
+        match &self.sink {
+            Sink::Webhook { url, headers } => {
+                let mut payload = serde_json::to_value(notification)?;
+                payload["message"] = Value::from(message);
+                let client = Client::new();
+                let mut req = client.post(url).json(&payload)?;
+                for (name, value) in headers.iter() {
+                    req = req.header(name, value);
+                }
+                check_response(url, req.send().await)
+            }
+            Sink::Slack { url } => {
+                let client = Client::new();
+                let req = client.post(url).json(&json!({ "text": message }))?;
+                check_response(url, req.send().await)
+            }
+            Sink::Email { smtp_host, smtp_port, tls, username, password, from, to, subject } => {
+                let mut email = Message::builder()
+                    .from(from.parse::<Mailbox>().context(f!("invalid sender `{from}`"))?)
+                    .subject(notification.vars.render(subject.as_deref().unwrap_or(DEFAULT_SUBJECT)));
+                for to in to.iter() {
+                    email = email.to(to.parse::<Mailbox>().context(f!("invalid recipient `{to}`"))?);
+                }
+                let email = email.body(message)?;
+                let mut transport = match tls {
+                    SmtpTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(smtp_host)?,
+                    SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(smtp_host)?,
+                    SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(smtp_host),
+                };
+                if let Some(port) = smtp_port {
+                    transport = transport.port(*port);
+                }
+                if let (Some(username), Some(password)) = (username, password) {
+                    transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+                }
+                transport.build().send(email).await.context(f!("failed to send the email through {smtp_host}"))?;
+                Ok(())
+            }
+            Sink::Command { command } => {
+                let Some((program, args)) = command.split_first() else { bail!("empty notification command") };
+                let mut payload = serde_json::to_value(notification)?;
+                payload["message"] = Value::from(message.clone());
+                let mut child = Command::new(program)
+                    .args(args)
+                    .env("NOTIFY_MESSAGE", &message)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::piped())
+                    .spawn()
+                    .context(f!("failed to run `{program}`"))?;
+                if let Some(mut stdin) = child.stdin.take() {
+                    let _ = stdin.write_all(&serde_json::to_vec(&payload)?).await;
+                }
+                let out = child.wait_with_output().await?;
+                if !out.status.success() {
+                    bail!("`{program}` exited with {}: {}", out.status, String::from_utf8_lossy(&out.stderr).trim());
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn check_response(url: &str, res: std::result::Result<ClientResponse, ClientError>) -> Result<()> {
+    let res = res.context(f!("failed to call {url}"))?;
+    if !res.is_success() {
+        bail!("{url} answered {} {}", res.status, res.reason);
+    }
+    Ok(())
+}
+
+impl Notification {
+    pub fn new(deployment: Option<u64>, status: Status, target: &DeployTarget, error: Option<String>) -> Self {
+        let vars = Variables::of(target)
+            .with("deployment", deployment.map(|id| id.to_string()).unwrap_or_default())
+            .with("status", status.to_string())
+            .with("error", error.clone().unwrap_or_default());
+        Self {
+            deployment,
+            status,
+            listener: target.listener.clone(),
+            services: target.services.clone(),
+            error,
+            event: Variables::of(target).iter().map(|(name, value)| (name.to_owned(), Value::from(value))).collect(),
+            vars,
+        }
+    }
+
+    /// Delivers the notification in the background to the notifiers of the listener subscribed to its status
+    pub fn send(self) {
+        let config = Config::global();
+        let listener = &config.listeners[&self.listener];
+        let notifiers = config
+            .notifications
+            .iter()
+            .filter(|(name, _)| listener.notify.as_ref().is_none_or(|names| names.contains(name)))
+            .filter(|(_, notifier)| notifier.on.contains(&self.status))
+            .collect::<Vec<_>>();
+        if notifiers.is_empty() {
+            return;
+        }
+        let notification = Arc::new(self);
+        for (name, notifier) in notifiers {
+            task::spawn(deliver_with_retry(name, notifier, notification.clone()));
+        }
+    }
+}
+
+async fn deliver_with_retry(name: &'static str, notifier: &'static Notifier, notification: Arc<Notification>) {
+    let mut delay = Duration::from_secs(notifier.backoff).min(MAX_BACKOFF);
+    for attempt in 0..=notifier.retries {
+        match notifier.deliver(&notification).await {
+            Ok(()) => {
+                println!("- notification '{name}' sent");
+                return;
+            }
+            Err(err) if attempt < notifier.retries => {
+                eprintln!("notification '{name}' failed, retrying in {}s: {err:#}", delay.as_secs());
+                tokio::time::sleep(delay).await;
+                delay = next_backoff(delay);
+            }
+            Err(err) => {
+                let message = f!("notification '{name}' failed after {} attempts: {err:#}", attempt + 1);
+                eprintln!("{message}");
+                Activity::error(message).publish();
+            }
+        }
+    }
+}
+
+fn next_backoff(delay: Duration) -> Duration {
+    delay.saturating_mul(2).min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webhook::{EventAction, RegistryEvent};
+    use http_tokio::{serve, Handler, Request, Response, StatusCode};
+    use std::sync::Mutex;
+    use tokio::net::TcpListener;
+
+    /// A request received by the stand-in: path, `X-Token` header and JSON body
+    type Received = (String, Option<String>, Value);
+
+    /// A local HTTP stand-in of the notified services, `/flaky` fails the first two requests
+    struct StandIn {
+        received: Arc<Mutex<Vec<Received>>>,
+    }
+
+    impl Handler for StandIn {
+        async fn call(&self, req: Request, res: &mut Response) {
+            let body = req.json::<Value>().unwrap_or_default();
+            let hits = {
+                let mut received = self.received.lock().unwrap();
+                received.push((req.path.clone(), req.header("X-Token"), body));
+                received.iter().filter(|(path, _, _)| *path == req.path).count()
+            };
+            if req.path == "/flaky" && hits <= 2 {
+                return res.status(StatusCode::InternalServerError).send("down").await;
+            }
+            res.send("ok").await
+        }
+    }
+
+    /// The base url of a started stand-in and the requests it receives
+    async fn stand_in() -> (String, Arc<Mutex<Vec<Received>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = f!("http://{}", listener.local_addr().unwrap());
+        let received = Arc::new(Mutex::new(vec![]));
+        task::spawn(serve(listener, StandIn { received: received.clone() }));
+        (url, received)
+    }
+
+    fn notification() -> Notification {
+        Config::init_test();
+        let target = DeployTarget {
+            listener: "api".into(),
+            compose_path: "/srv/api/compose.yml".into(),
+            services: vec!["api".into()],
+            event: RegistryEvent {
+                action: EventAction::Push,
+                host: "registry.example.com".into(),
+                repository: "app".into(),
+                tag: Some("v2".into()),
+                digest: Some("sha256:abc".into()),
+                media_type: None,
+            },
+        };
+        Notification::new(Some(7), Status::Failed, &target, Some("pull failed".into()))
+    }
+
+    fn notifier(sink: Sink, retries: u32) -> Notifier {
+        Notifier {
+            sink,
+            template: Some("{{status}} {{image}}: {{error}}".into()),
+            on: Notifier::default_on(),
+            retries,
+            backoff: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn webhook_posts_the_notification_with_its_message() {
+        let (url, received) = stand_in().await;
+        let headers = HashMap::from([("X-Token".to_owned(), "secret".to_owned())]);
+        let notifier = notifier(Sink::Webhook { url: f!("{url}/hook"), headers }, 0);
+        notifier.deliver(&notification()).await.unwrap();
+
+        let received = received.lock().unwrap();
+        let (path, token, body) = &received[0];
+        assert_eq!(path, "/hook");
+        assert_eq!(token.as_deref(), Some("secret"));
+        assert_eq!(body["deployment"], 7);
+        assert_eq!(body["status"], "failed");
+        assert_eq!(body["services"], json!(["api"]));
+        assert_eq!(body["event"]["tag"], "v2");
+        assert_eq!(body["message"], "failed 10.0.0.5:5000/app:v2: pull failed");
+    }
+
+    #[tokio::test]
+    async fn slack_posts_the_message_as_text() {
+        let (url, received) = stand_in().await;
+        let notifier = notifier(Sink::Slack { url: f!("{url}/slack") }, 0);
+        notifier.deliver(&notification()).await.unwrap();
+        assert_eq!(received.lock().unwrap()[0].2, json!({ "text": "failed 10.0.0.5:5000/app:v2: pull failed" }));
+    }
+
+    #[tokio::test]
+    async fn failed_deliveries_are_retried() {
+        let (url, received) = stand_in().await;
+        let notifier = notifier(Sink::Slack { url: f!("{url}/flaky") }, 0);
+        assert!(notifier.deliver(&notification()).await.is_err());
+
+        let notifier = Box::leak(Box::new(self::notifier(Sink::Slack { url: f!("{url}/flaky") }, 3)));
+        deliver_with_retry("flaky", notifier, Arc::new(notification())).await;
+        // the first failed delivery above, the second failed one and the successful third
+        assert_eq!(received.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn command_gets_the_notification_on_stdin() {
+        let file = std::env::temp_dir().join(f!("notify-command-{}.json", std::process::id()));
+        let script = "cat > \"$1\" && [ \"$NOTIFY_MESSAGE\" = 'failed 10.0.0.5:5000/app:v2: pull failed' ]";
+        let command = ["sh", "-c", script, "sh", file.to_str().unwrap()].map(String::from).to_vec();
+        notifier(Sink::Command { command }, 0).deliver(&notification()).await.unwrap();
+        let payload = serde_json::from_slice::<Value>(&std::fs::read(&file).unwrap()).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(payload["listener"], "api");
+        assert_eq!(payload["message"], "failed 10.0.0.5:5000/app:v2: pull failed");
+
+        let command = ["sh", "-c", "echo unreachable >&2; exit 3"].map(String::from).to_vec();
+        let err = notifier(Sink::Command { command }, 0).deliver(&notification()).await.unwrap_err();
+        assert!(f!("{err:#}").contains("unreachable"), "{err:#}");
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        assert_eq!(next_backoff(Duration::from_secs(2)), Duration::from_secs(4));
+        assert_eq!(next_backoff(Duration::from_secs(200)), MAX_BACKOFF);
+        assert_eq!(next_backoff(Duration::MAX), MAX_BACKOFF);
+    }
+}
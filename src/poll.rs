@@ -0,0 +1,86 @@
+use crate::{
+    activity::Activity,
+    compose::ComposeCmd,
+    config::{Config, Listener},
+    http::handle_registry_events,
+    image::ImageRef,
+    prelude::*,
+    registry::Registry,
+    webhook::{EventAction, RegistryEvent},
+};
+use std::{collections::HashMap, time::Duration};
+use tokio::task;
+
+/// Starts a polling task for each listener with a `poll_interval`
+pub fn start() {
+    for (name, listener) in Config::global().listeners.iter() {
+        if let Some(interval) = listener.poll_interval() {
+            println!("polling the images of listener '{name}' every {}s", interval.as_secs());
+            task::spawn(poll_listener(name, listener, interval));
+        }
+    }
+}
+
+async fn poll_listener(name: &'static str, listener: &'static Listener, interval: Duration) {
+    let mut images = Vec::new();
+    for (image, service) in listener.itos.iter() {
+        // the registry is queried on the host written in the compose file, the canonical host of an alias may not be reachable
+        let image = match listener.compose_image(service).map(ImageRef::parse) {
+            Some(Ok(written)) => written,
+            _ => image.clone(),
+        };
+        match image.tag.clone() {
+            Some(tag) if image.digest.is_none() => images.push((image, tag, service)),
+            _ => eprintln!("listener '{name}': image '{image}' is pinned to a digest, it is not polled"),
+        }
+    }
+    // one client per registry keeps the tokens between polls
+    let mut registries = HashMap::<String, Registry>::new();
+    // the digests already sent to the deployer, a slow deployment is not queued again
+    let mut triggered = HashMap::<String, String>::new();
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let mut events = vec![];
+        for (image, tag, service) in images.iter() {
+            let host = image.host();
+            let registry = registries.entry(host.clone()).or_insert_with(|| Registry::alias_of(&host, listener.canonical_host(&host)));
+            match outdated_digest(registry, listener, image, tag, service).await {
+                Ok(Some(digest)) if triggered.get(*service) != Some(&digest) => {
+                    println!("- {image} has a new digest {digest}");
+                    triggered.insert(service.to_string(), digest.clone());
+                    events.push(RegistryEvent {
+                        action: EventAction::Push,
+                        host: image.host(),
+                        repository: image.path(),
+                        tag: Some(tag.to_string()),
+                        digest: Some(digest),
+                        media_type: None,
+                    });
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    eprintln!("listener '{name}': could not poll service '{service}': {err:#}");
+                    Activity::error(f!("listener '{name}': could not poll service '{service}': {err:#}")).publish();
+                }
+            }
+        }
+        if events.is_empty() {
+            continue;
+        }
+        // the same path as the registry notifications, other listeners of the image are updated too
+        if let Err(err) = handle_registry_events(events).await {
+            eprintln!("{:?}", err);
+        }
+    }
+}
+
+/// The digest of the tag when the running container uses another one, `None` if it is up to date or not running
+async fn outdated_digest(registry: &Registry, listener: &Listener, image: &ImageRef, tag: &str, service: &str) -> Result<Option<String>> {
+    let running = ComposeCmd::new(&listener.compose.path).running_digests(service).await?;
+    if running.is_empty() {
+        return Ok(None);
+    }
+    let digest = registry.digest(&image.path(), tag).await?;
+    Ok((!running.contains(&digest)).then_some(digest))
+}
@@ -0,0 +1,62 @@
+use crate::{config::Config, prelude::*};
+use anyhow::{bail, Context};
+use http_tokio::{Client, ClientResponse};
+
+/// Manifest media types accepted, multi-platform images resolve to their index
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+
+/// The Registry HTTP API v2 of a single registry, with the credentials configured in `registries`
+pub struct Registry {
+    host: String,
+    scheme: &'static str,
+    client: Client,
+}
+
+impl Registry {
+    /// The registry at `host`, with the credentials and settings of the registry it is an alias of
+    pub fn new(host: &str) -> Self {
+        Self::alias_of(host, Config::global().canonical_host(host))
+    }
+
+    /// The registry at `host`, with the credentials and settings configured for `canonical`
+    pub fn alias_of(host: &str, canonical: &str) -> Self {
+        let config = Config::global().registries.get(canonical);
+        let mut client = Client::new();
+        if let Some((username, password)) = config.and_then(|c| c.username.as_ref().zip(c.password.as_ref())) {
+            client = client.basic_auth(username, password);
+        }
+        let insecure = config.is_some_and(|c| c.insecure);
+        // Docker Hub images are named `docker.io/...` but served by another host
+        let host = if host == "docker.io" { "registry-1.docker.io" } else { host };
+        Self { host: host.into(), scheme: if insecure { "http" } else { "https" }, client }
+    }
+
+    /// Whether the host has an entry in `registries`
+    pub fn is_configured(host: &str) -> bool {
+        Config::global().registries.contains_key(Config::global().canonical_host(host))
+    }
+
+    /// Resolves a tag to the digest of its manifest without downloading it
+    pub async fn digest(&self, repository: &str, reference: &str) -> Result<String> {
+        let res = self.send_manifest_request("HEAD", repository, reference).await?;
+        res.header("Docker-Content-Digest")
+            .map(String::from)
+            .context("registry did not send Docker-Content-Digest")
+    }
+
+    async fn send_manifest_request(&self, method: &str, repository: &str, reference: &str) -> Result<ClientResponse> {
+        let url = f!("{}://{}/v2/{repository}/manifests/{reference}", self.scheme, self.host);
+        let res = self.client.request(method, &url)
+            .header("Accept", MANIFEST_TYPES)
+            .send().await
+            .context(f!("could not reach registry {}", self.host))?;
+        match res.status {
+            200 => Ok(res),
+            404 => bail!("manifest {repository}:{reference} not found on {}", self.host),
+            status => bail!("registry {} answered {status} {} for {repository}:{reference}", self.host, res.reason),
+        }
+    }
+}
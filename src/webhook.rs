@@ -0,0 +1,529 @@
+use crate::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashSet,
+    fmt::{self, Display},
+};
+
+/// What happened to the image, the reaction of each listener is configured with `on`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventAction {
+    Push,
+    Pull,
+    Delete,
+    /// a blob mounted from another repository of the registry
+    Mount,
+}
+
+impl EventAction {
+    fn from_distribution(action: &str) -> Option<Self> {
+        match action {
+            "push" => Some(Self::Push),
+            "pull" => Some(Self::Pull),
+            "delete" => Some(Self::Delete),
+            "mount" => Some(Self::Mount),
+            _ => None,
+        }
+    }
+}
+
+impl Display for EventAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self {
+            Self::Push => "push",
+            Self::Pull => "pull",
+            Self::Delete => "delete",
+            Self::Mount => "mount",
+        };
+        f.write_str(action)
+    }
+}
+
+/// An event normalized from any of the supported webhook payloads
+#[derive(Debug, Clone)]
+pub struct RegistryEvent {
+    pub action: EventAction,
+    /// registry host as used by the pusher
+    pub host: String,
+    pub repository: String,
+    /// missing for manifests pushed by digest
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+    /// `target.mediaType` of the distribution notifications, the other formats only notify manifests
+    pub media_type: Option<String>,
+}
+
+/// What an event's `media_type` refers to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MediaKind {
+    /// a multi-platform manifest list or OCI index
+    Index,
+    Manifest,
+    /// a layer or config blob
+    Blob,
+}
+
+impl RegistryEvent {
+    pub fn media_kind(&self) -> MediaKind {
+        match self.media_type.as_deref() {
+            Some("application/vnd.oci.image.index.v1+json" | "application/vnd.docker.distribution.manifest.list.v2+json") => MediaKind::Index,
+            Some(media_type) if media_type.contains(".manifest.") => MediaKind::Manifest,
+            Some(_) => MediaKind::Blob,
+            None => MediaKind::Manifest,
+        }
+    }
+}
+
+/// The events that can trigger a reaction: blob events other than mounts are dropped, and so are the platform
+/// manifests of a tag whose index is in the same batch, as well as repeated events
+pub fn top_level(events: Vec<RegistryEvent>) -> Vec<RegistryEvent> {
+    let key = |event: &RegistryEvent| (event.action, event.host.clone(), event.repository.clone());
+    let indexes = events
+        .iter()
+        .filter(|event| event.media_kind() == MediaKind::Index)
+        .map(|event| (key(event), event.tag.clone()))
+        .collect::<HashSet<_>>();
+    let mut seen = HashSet::new();
+    events
+        .into_iter()
+        .filter(|event| match event.media_kind() {
+            MediaKind::Index => true,
+            // the platform manifests are pushed by digest, a tagged manifest is only a child of the index of its tag
+            MediaKind::Manifest => match &event.tag {
+                Some(_) => !indexes.contains(&(key(event), event.tag.clone())),
+                None => !indexes.iter().any(|(index, _)| *index == key(event)),
+            },
+            MediaKind::Blob => event.action == EventAction::Mount,
+        })
+        .filter(|event| seen.insert((key(event), event.tag.clone(), event.digest.clone())))
+        .collect()
+}
+
+/// The payloads understood by the server, selected with `/webhooks/<format>` or detected from the body
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WebhookFormat {
+    /// the CNCF distribution notification envelope, also sent by the GitLab registry
+    Distribution,
+    Harbor,
+    DockerHub,
+    /// Gitea and Forgejo package events
+    Gitea,
+    /// GitHub `registry_package` and `package` events for GHCR
+    Github,
+}
+
+impl WebhookFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "distribution" | "gitlab" => Some(Self::Distribution),
+            "harbor" => Some(Self::Harbor),
+            "dockerhub" => Some(Self::DockerHub),
+            "gitea" | "forgejo" => Some(Self::Gitea),
+            "github" | "ghcr" => Some(Self::Github),
+            _ => None,
+        }
+    }
+
+    /// Guesses the format from the fields that only one of the payloads has
+    pub fn detect(payload: &Value) -> Option<Self> {
+        let has = |field: &str| payload.get(field).is_some();
+        // GitHub `package` events also have `package` and `action`, only their versions have container metadata
+        let github_package = payload.pointer("/package/package_version/container_metadata").is_some();
+        if has("events") {
+            Some(Self::Distribution)
+        } else if has("event_data") && has("type") {
+            Some(Self::Harbor)
+        } else if has("push_data") {
+            Some(Self::DockerHub)
+        } else if has("registry_package") || github_package {
+            Some(Self::Github)
+        } else if has("package") && has("action") {
+            Some(Self::Gitea)
+        } else {
+            None
+        }
+    }
+
+    /// The events of the payload, the ones without an [`EventAction`] are ignored
+    pub fn parse(self, payload: Value) -> serde_json::Result<Vec<RegistryEvent>> {
+        Ok(match self {
+            Self::Distribution => serde_json::from_value::<DistributionEnvelope>(payload)?.into_events(),
+            Self::Harbor => serde_json::from_value::<HarborPayload>(payload)?.into_events(),
+            Self::DockerHub => serde_json::from_value::<DockerHubPayload>(payload)?.into_events(),
+            Self::Gitea => serde_json::from_value::<GiteaPayload>(payload)?.into_events(),
+            Self::Github => serde_json::from_value::<GithubPayload>(payload)?.into_events(),
+        })
+    }
+}
+
+/// `registry.example.com` of `registry.example.com/library/app:latest` or `https://registry.example.com/...`
+fn host_of(url: &str) -> Option<String> {
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    without_scheme.split('/').next().filter(|host| !host.is_empty()).map(String::from)
+}
+
+// distribution
+
+#[derive(Deserialize, Debug)]
+struct DistributionEnvelope {
+    events: Vec<DistributionEvent>,
+}
+
+#[derive(Deserialize, Debug)]
+struct DistributionEvent {
+    action: String,
+    target: DistributionTarget,
+    request: DistributionRequest,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct DistributionTarget {
+    media_type: Option<String>,
+    repository: String,
+    tag: Option<String>,
+    digest: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct DistributionRequest {
+    host: String,
+}
+
+impl DistributionEnvelope {
+    fn into_events(self) -> Vec<RegistryEvent> {
+        self.events
+            .into_iter()
+            .filter_map(|event| {
+                Some(RegistryEvent {
+                    action: EventAction::from_distribution(&event.action)?,
+                    host: event.request.host,
+                    repository: event.target.repository,
+                    tag: event.target.tag,
+                    digest: event.target.digest,
+                    media_type: event.target.media_type,
+                })
+            })
+            .collect()
+    }
+}
+
+// harbor
+
+#[derive(Deserialize, Debug)]
+struct HarborPayload {
+    #[serde(rename = "type")]
+    kind: String,
+    event_data: HarborEventData,
+}
+
+#[derive(Deserialize, Debug)]
+struct HarborEventData {
+    resources: Vec<HarborResource>,
+    repository: HarborRepository,
+}
+
+#[derive(Deserialize, Debug)]
+struct HarborResource {
+    digest: Option<String>,
+    tag: Option<String>,
+    resource_url: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct HarborRepository {
+    repo_full_name: String,
+}
+
+impl HarborPayload {
+    fn into_events(self) -> Vec<RegistryEvent> {
+        let action = match self.kind.as_str() {
+            "PUSH_ARTIFACT" => EventAction::Push,
+            "PULL_ARTIFACT" => EventAction::Pull,
+            "DELETE_ARTIFACT" => EventAction::Delete,
+            _ => return vec![],
+        };
+        let repository = self.event_data.repository.repo_full_name;
+        self.event_data
+            .resources
+            .into_iter()
+            .filter_map(|resource| {
+                Some(RegistryEvent {
+                    action,
+                    host: host_of(&resource.resource_url)?,
+                    repository: repository.clone(),
+                    tag: resource.tag.filter(|tag| !tag.is_empty()),
+                    digest: resource.digest,
+                    media_type: None,
+                })
+            })
+            .collect()
+    }
+}
+
+// docker hub
+
+#[derive(Deserialize, Debug)]
+struct DockerHubPayload {
+    push_data: DockerHubPushData,
+    repository: DockerHubRepository,
+}
+
+#[derive(Deserialize, Debug)]
+struct DockerHubPushData {
+    tag: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct DockerHubRepository {
+    repo_name: String,
+}
+
+impl DockerHubPayload {
+    fn into_events(self) -> Vec<RegistryEvent> {
+        vec![RegistryEvent {
+            action: EventAction::Push,
+            host: "docker.io".into(),
+            repository: self.repository.repo_name,
+            tag: Some(self.push_data.tag),
+            digest: None,
+            media_type: None,
+        }]
+    }
+}
+
+// gitea
+
+#[derive(Deserialize, Debug)]
+struct GiteaPayload {
+    action: String,
+    package: GiteaPackage,
+}
+
+#[derive(Deserialize, Debug)]
+struct GiteaPackage {
+    #[serde(rename = "type")]
+    kind: String,
+    name: String,
+    version: String,
+    owner: GiteaOwner,
+    html_url: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct GiteaOwner {
+    login: String,
+}
+
+impl GiteaPayload {
+    fn into_events(self) -> Vec<RegistryEvent> {
+        let package = self.package;
+        let action = match self.action.as_str() {
+            "created" => EventAction::Push,
+            "deleted" => EventAction::Delete,
+            _ => return vec![],
+        };
+        if package.kind != "container" {
+            return vec![];
+        }
+        let Some(host) = host_of(&package.html_url) else {
+            return vec![];
+        };
+        // untagged manifests are published with their digest as version
+        let (tag, digest) = match package.version.starts_with("sha256:") {
+            true => (None, Some(package.version)),
+            false => (Some(package.version), None),
+        };
+        vec![RegistryEvent {
+            action,
+            host,
+            repository: f!("{}/{}", package.owner.login, package.name).to_lowercase(),
+            tag,
+            digest,
+            media_type: None,
+        }]
+    }
+}
+
+// github
+
+#[derive(Deserialize, Debug)]
+struct GithubPayload {
+    action: String,
+    #[serde(alias = "package")]
+    registry_package: GithubPackage,
+}
+
+#[derive(Deserialize, Debug)]
+struct GithubPackage {
+    name: String,
+    namespace: String,
+    package_type: String,
+    package_version: GithubPackageVersion,
+}
+
+#[derive(Deserialize, Debug)]
+struct GithubPackageVersion {
+    version: String,
+    container_metadata: Option<GithubContainerMetadata>,
+    package_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct GithubContainerMetadata {
+    tag: Option<GithubTag>,
+}
+
+#[derive(Deserialize, Debug)]
+struct GithubTag {
+    name: String,
+    digest: Option<String>,
+}
+
+impl GithubPayload {
+    fn into_events(self) -> Vec<RegistryEvent> {
+        let package = self.registry_package;
+        if self.action != "published" || !package.package_type.eq_ignore_ascii_case("container") {
+            return vec![];
+        }
+        let version = package.package_version;
+        let tag = version.container_metadata.and_then(|metadata| metadata.tag).filter(|tag| !tag.name.is_empty());
+        let host = version.package_url.as_deref().and_then(host_of).unwrap_or_else(|| "ghcr.io".into());
+        let (tag, digest) = match tag {
+            Some(tag) => (Some(tag.name), tag.digest.or(Some(version.version))),
+            None => (None, Some(version.version)),
+        };
+        vec![RegistryEvent {
+            action: EventAction::Push,
+            host,
+            repository: f!("{}/{}", package.namespace, package.name).to_lowercase(),
+            tag,
+            digest,
+            media_type: None,
+        }]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INDEX: &str = "application/vnd.oci.image.index.v1+json";
+    const MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+    const LAYER: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+
+    fn event(action: EventAction, tag: Option<&str>, digest: &str, media_type: &str) -> RegistryEvent {
+        RegistryEvent {
+            action,
+            host: "registry.example.com".into(),
+            repository: "app".into(),
+            tag: tag.map(String::from),
+            digest: Some(digest.into()),
+            media_type: Some(media_type.into()),
+        }
+    }
+
+    #[test]
+    fn top_level_keeps_indexes_other_tags_and_mounts() {
+        let events = vec![
+            event(EventAction::Push, None, "sha256:amd64", MANIFEST),
+            event(EventAction::Push, None, "sha256:arm64", MANIFEST),
+            event(EventAction::Push, Some("a"), "sha256:index", INDEX),
+            event(EventAction::Push, Some("a"), "sha256:index", INDEX),
+            event(EventAction::Push, Some("b"), "sha256:single", MANIFEST),
+            event(EventAction::Push, None, "sha256:layer", LAYER),
+            event(EventAction::Mount, None, "sha256:mounted", LAYER),
+        ];
+        let kept = top_level(events).into_iter().map(|event| (event.action, event.digest.unwrap())).collect::<Vec<_>>();
+        assert_eq!(
+            kept,
+            vec![
+                (EventAction::Push, "sha256:index".to_owned()),
+                (EventAction::Push, "sha256:single".to_owned()),
+                (EventAction::Mount, "sha256:mounted".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn top_level_keeps_manifests_without_index() {
+        let events = vec![event(EventAction::Push, Some("a"), "sha256:single", MANIFEST), event(EventAction::Push, None, "sha256:other", MANIFEST)];
+        assert_eq!(top_level(events).len(), 2);
+    }
+
+    // payloads captured from each registry, trimmed of the fields the parsers skip
+    const DISTRIBUTION: &str = r#"{"events":[{"id":"320678d8-ca14-430f-8bb6-4ca139cd83f7","timestamp":"2024-05-01T10:00:00.000Z","action":"push",
+        "target":{"mediaType":"application/vnd.docker.distribution.manifest.v2+json","size":708,"digest":"sha256:fea8895f450959fa676bcc1df0611ea93823a735a01205fd8622846041d0c7cf",
+        "length":708,"repository":"team/app","url":"https://registry.example.com/v2/team/app/manifests/sha256:fea8895f450959fa676bcc1df0611ea93823a735a01205fd8622846041d0c7cf","tag":"v1.2"},
+        "request":{"id":"6df24a34-0959-4923-81ca-14f09767db19","addr":"192.168.64.11:42961","host":"registry.example.com","method":"PUT","useragent":"docker/26.1.1"},
+        "actor":{},"source":{"addr":"registry:5000","instanceID":"a53db899-3b4b-4a62-a067-8dd013beaca4"}}]}"#;
+    const HARBOR: &str = r#"{"type":"PUSH_ARTIFACT","occur_at":1714557600,"operator":"admin","event_data":{
+        "resources":[{"digest":"sha256:fea8895f450959fa676bcc1df0611ea93823a735a01205fd8622846041d0c7cf","tag":"v1.2","resource_url":"harbor.example.com/team/app:v1.2"}],
+        "repository":{"date_created":1714557600,"name":"app","namespace":"team","repo_full_name":"team/app","repo_type":"private"}}}"#;
+    const DOCKER_HUB: &str = r#"{"callback_url":"https://registry.hub.docker.com/u/team/app/hook/2141b5bi5i5b02bec211i4eeih0242eg11000a/",
+        "push_data":{"pushed_at":1714557600,"pusher":"alice","tag":"v1.2"},
+        "repository":{"comment_count":0,"date_created":1714000000,"description":"","is_official":false,"is_private":true,"is_trusted":false,"name":"app",
+        "namespace":"team","owner":"team","repo_name":"team/app","repo_url":"https://hub.docker.com/r/team/app","star_count":0,"status":"Active"}}"#;
+    const GITEA: &str = r#"{"action":"created","package":{"id":12,"owner":{"id":3,"login":"Team","full_name":"","email":""},"repository":null,
+        "creator":{"id":1,"login":"alice"},"type":"container","name":"App","version":"v1.2",
+        "html_url":"https://gitea.example.com/Team/-/packages/container/App/v1.2","created_at":"2024-05-01T10:00:00Z"},"sender":{"id":1,"login":"alice"}}"#;
+    const GITHUB_REGISTRY_PACKAGE: &str = r#"{"action":"published","registry_package":{"id":1,"name":"app","namespace":"Team","description":"","ecosystem":"CONTAINER",
+        "package_type":"CONTAINER","html_url":"https://github.com/orgs/Team/packages/container/package/app",
+        "package_version":{"id":2,"version":"sha256:fea8895f450959fa676bcc1df0611ea93823a735a01205fd8622846041d0c7cf","name":"sha256:fea8895f450959fa676bcc1df0611ea93823a735a01205fd8622846041d0c7cf",
+        "package_url":"ghcr.io/team/app:v1.2","container_metadata":{"tag":{"name":"v1.2","digest":"sha256:fea8895f450959fa676bcc1df0611ea93823a735a01205fd8622846041d0c7cf"},"labels":{},"manifest":{}}},
+        "registry":{"about_url":"https://docs.github.com/packages","name":"GitHub CodeSpaces registry","type":"docker","url":"https://ghcr.io/team","vendor":"GitHub Inc"}},
+        "sender":{"login":"alice","id":1}}"#;
+    const GITHUB_PACKAGE: &str = r#"{"action":"published","package":{"id":1,"name":"app","namespace":"Team","description":"","ecosystem":"CONTAINER",
+        "package_type":"CONTAINER","html_url":"https://github.com/orgs/Team/packages/container/package/app",
+        "package_version":{"id":2,"version":"sha256:fea8895f450959fa676bcc1df0611ea93823a735a01205fd8622846041d0c7cf","name":"sha256:fea8895f450959fa676bcc1df0611ea93823a735a01205fd8622846041d0c7cf",
+        "package_url":"ghcr.io/team/app:v1.2","container_metadata":{"tag":{"name":"v1.2","digest":"sha256:fea8895f450959fa676bcc1df0611ea93823a735a01205fd8622846041d0c7cf"},"labels":{},"manifest":{}}},
+        "registry":{"about_url":"https://docs.github.com/packages","name":"GitHub CodeSpaces registry","type":"docker","url":"https://ghcr.io/team","vendor":"GitHub Inc"}},
+        "repository":{"id":3,"full_name":"Team/app"},"sender":{"login":"alice","id":1}}"#;
+    const DIGEST: &str = "sha256:fea8895f450959fa676bcc1df0611ea93823a735a01205fd8622846041d0c7cf";
+
+    /// `(host, repository, tag, digest)` of a push event
+    type Pushed = (String, String, Option<String>, Option<String>);
+
+    /// The detected format and the push events of the payload
+    fn detect_and_parse(payload: &str) -> (Option<WebhookFormat>, Vec<Pushed>) {
+        let payload = serde_json::from_str::<Value>(payload).unwrap();
+        let format = WebhookFormat::detect(&payload);
+        let events = format.map(|format| format.parse(payload).unwrap()).unwrap_or_default();
+        let events = events.into_iter().inspect(|event| assert_eq!(event.action, EventAction::Push));
+        (format, events.map(|event| (event.host, event.repository, event.tag, event.digest)).collect())
+    }
+
+    fn push(host: &str, repository: &str, digest: Option<&str>) -> Vec<Pushed> {
+        vec![(host.into(), repository.into(), Some("v1.2".into()), digest.map(String::from))]
+    }
+
+    #[test]
+    fn distribution_payloads_are_detected() {
+        assert_eq!(detect_and_parse(DISTRIBUTION), (Some(WebhookFormat::Distribution), push("registry.example.com", "team/app", Some(DIGEST))));
+    }
+
+    #[test]
+    fn harbor_payloads_are_detected() {
+        assert_eq!(detect_and_parse(HARBOR), (Some(WebhookFormat::Harbor), push("harbor.example.com", "team/app", Some(DIGEST))));
+    }
+
+    #[test]
+    fn docker_hub_payloads_are_detected() {
+        assert_eq!(detect_and_parse(DOCKER_HUB), (Some(WebhookFormat::DockerHub), push("docker.io", "team/app", None)));
+    }
+
+    #[test]
+    fn gitea_payloads_are_detected() {
+        assert_eq!(detect_and_parse(GITEA), (Some(WebhookFormat::Gitea), push("gitea.example.com", "team/app", None)));
+    }
+
+    #[test]
+    fn github_payloads_are_detected() {
+        let expected = (Some(WebhookFormat::Github), push("ghcr.io", "team/app", Some(DIGEST)));
+        assert_eq!(detect_and_parse(GITHUB_REGISTRY_PACKAGE), expected);
+        // `package` events have the `package` and `action` of the Gitea payloads
+        assert_eq!(detect_and_parse(GITHUB_PACKAGE), expected);
+    }
+}